@@ -0,0 +1,181 @@
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+
+use crate::config::{Artifact, Project};
+use crate::utils::{modified_secs, sha256_file};
+
+/// 需要上传的文件，remote为相对于部署目录的路径
+#[derive(Debug, Clone)]
+pub struct UploadFile {
+    pub local: PathBuf,
+    pub remote: PathBuf,
+    pub size: u64,
+    pub mode: i32,
+    pub mtime: u64,
+    pub sha256: String,
+    /// 目录或通配符对应的远程目录，相对于部署目录，单个文件为None
+    pub root: Option<PathBuf>,
+}
+
+impl UploadFile {
+    fn new(local: PathBuf, remote: PathBuf, root: Option<PathBuf>) -> Result<UploadFile> {
+        let metadata = std::fs::metadata(&local)?;
+        let (size, mode, mtime) = (metadata.len(), UploadFile::mode(&metadata), modified_secs(&metadata));
+        let sha256 = sha256_file(&local)?;
+        Ok(UploadFile { local, remote, size, mode, mtime, sha256, root })
+    }
+
+    #[cfg(unix)]
+    fn mode(metadata: &std::fs::Metadata) -> i32 {
+        use std::os::unix::fs::PermissionsExt;
+        (metadata.permissions().mode() & 0o7777) as i32
+    }
+
+    #[cfg(not(unix))]
+    fn mode(_metadata: &std::fs::Metadata) -> i32 {
+        0o644
+    }
+
+    fn is_glob(path: &str) -> bool {
+        path.contains(['*', '?', '['])
+    }
+
+    /// 通配符之前的目录，例如 `dist/**` 为 `dist`
+    fn glob_base(path: &str) -> PathBuf {
+        Path::new(path).components()
+            .take_while(|x| !matches!(x, Component::Normal(name) if UploadFile::is_glob(&name.to_string_lossy())))
+            .collect()
+    }
+
+    /// 目录下的全部文件，返回(本地路径, 相对于dir父目录的路径)
+    fn walk(dir: &Path, relative: &Path, files: &mut Vec<(PathBuf, PathBuf)>) -> Result<()> {
+        let mut entries: Vec<PathBuf> = std::fs::read_dir(dir)?.map(|x| x.map(|entry| entry.path())).collect::<std::io::Result<_>>()?;
+        entries.sort();
+        for path in entries {
+            let relative = relative.join(path.file_name().unwrap());
+            if path.is_dir() {
+                UploadFile::walk(&path, &relative, files)?;
+            } else {
+                files.push((path, relative));
+            }
+        }
+        Ok(())
+    }
+
+    /// 查找单个部署文件配置对应的文件，目录上传到dest下的同名目录，通配符匹配的文件按相对于glob_base的路径上传到dest
+    pub fn resolve_artifact(source_dir: &Path, artifact: &Artifact) -> Result<Vec<UploadFile>> {
+        let dest = PathBuf::from(artifact.dest.clone().unwrap_or_default());
+        let mut files = vec![];
+        let root;
+        if UploadFile::is_glob(&artifact.path) {
+            root = Some(dest.clone());
+            let base = source_dir.join(UploadFile::glob_base(&artifact.path));
+            let mut pattern = source_dir.join(&artifact.path).to_string_lossy().to_string();
+            // `**` 只匹配目录，`dist/**` 按目录下的全部文件处理
+            if pattern.ends_with("**") {
+                pattern.push_str("/*");
+            }
+            for entry in glob::glob(&pattern)? {
+                let path = entry?;
+                if path.is_file() {
+                    let relative = path.strip_prefix(&base)?.to_path_buf();
+                    files.push((path, relative));
+                }
+            }
+        } else {
+            let path = source_dir.join(&artifact.path);
+            if path.is_dir() {
+                let name = PathBuf::from(path.file_name().unwrap_or_default());
+                root = Some(dest.join(&name));
+                UploadFile::walk(&path, &name, &mut files)?;
+            } else if path.is_file() {
+                let name = PathBuf::from(path.file_name().unwrap());
+                root = None;
+                files.push((path, name));
+            } else {
+                return Err(anyhow!("部署文件 {} 不存在", path.display()));
+            }
+        }
+        if files.is_empty() {
+            return Err(anyhow!("{} 没有匹配的文件", artifact.path));
+        }
+        files.into_iter().map(|(local, relative)| UploadFile::new(local, dest.join(relative), root.clone())).collect()
+    }
+
+    /// 根据artifacts配置查找需要上传的文件，未配置artifacts时上传target_name
+    pub fn resolve(project: &Project) -> Result<Vec<UploadFile>> {
+        let source_dir = Path::new(&project.source_dir);
+        let mut files = vec![];
+        for artifact in project.deploy_artifacts() {
+            files.extend(UploadFile::resolve_artifact(source_dir, &artifact)?);
+        }
+        Ok(files)
+    }
+
+    /// 部署文件的校验和，单个文件时为文件的SHA-256，多个文件时为全部文件路径和SHA-256的SHA-256
+    pub fn checksum(files: &[UploadFile]) -> String {
+        if let [file] = files {
+            return file.sha256.clone();
+        }
+        let mut hasher = Sha256::new();
+        for file in files {
+            hasher.update(file.remote.to_string_lossy().as_bytes());
+            hasher.update(file.sha256.as_bytes());
+        }
+        format!("{:x}", hasher.finalize())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn artifact(path: &str, dest: Option<&str>) -> Artifact {
+        Artifact { path: path.to_string(), dest: dest.map(|x| x.to_string()) }
+    }
+
+    fn remotes(files: &[UploadFile]) -> Vec<String> {
+        files.iter().map(|x| x.remote.to_string_lossy().to_string()).collect()
+    }
+
+    #[test]
+    fn glob_base_stops_at_first_wildcard() {
+        assert_eq!(UploadFile::glob_base("dist/**"), PathBuf::from("dist"));
+        assert_eq!(UploadFile::glob_base("lib/*.jar"), PathBuf::from("lib"));
+        assert_eq!(UploadFile::glob_base("build/out/*/app-?.jar"), PathBuf::from("build/out"));
+        assert_eq!(UploadFile::glob_base("*.jar"), PathBuf::new());
+    }
+
+    #[test]
+    fn directories_keep_their_name_and_globs_do_not() {
+        let dir = std::env::temp_dir().join(format!("deploy_tool-test-artifact-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(dir.join("dist/js")).unwrap();
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        for path in ["dist/index.html", "dist/js/app.js", "lib/a.jar", "lib/b.txt", "app.jar"] {
+            std::fs::write(dir.join(path), path).unwrap();
+        }
+
+        let files = UploadFile::resolve_artifact(&dir, &artifact("dist", None)).unwrap();
+        assert_eq!(remotes(&files), vec!["dist/index.html", "dist/js/app.js"]);
+        assert_eq!(files[0].root, Some(PathBuf::from("dist")));
+
+        let files = UploadFile::resolve_artifact(&dir, &artifact("dist/**", Some("web"))).unwrap();
+        assert_eq!(remotes(&files), vec!["web/index.html", "web/js/app.js"]);
+        assert_eq!(files[0].root, Some(PathBuf::from("web")));
+
+        let files = UploadFile::resolve_artifact(&dir, &artifact("lib/*.jar", Some("lib"))).unwrap();
+        assert_eq!(remotes(&files), vec!["lib/a.jar"]);
+
+        let files = UploadFile::resolve_artifact(&dir, &artifact("app.jar", None)).unwrap();
+        assert_eq!(remotes(&files), vec!["app.jar"]);
+        assert_eq!(files[0].root, None);
+        assert_eq!(files[0].sha256, format!("{:x}", Sha256::digest(b"app.jar")));
+
+        assert!(UploadFile::resolve_artifact(&dir, &artifact("missing.jar", None)).is_err());
+        assert!(UploadFile::resolve_artifact(&dir, &artifact("lib/*.war", None)).unwrap_err().to_string().contains("没有匹配的文件"));
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,96 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use regex::Regex;
+
+use crate::config::Project;
+use crate::manifest::Manifest;
+use crate::utils::{quote, SshUtil};
+
+/// 服务器上部署文件的备份，只备份target_name，文件名为 `target_name.时间戳`，例如 `app.jar.20210418-101500`，
+/// 部署记录同时备份为 `.deploy-项目名称.toml.时间戳`，恢复备份时一起恢复
+#[derive(Debug, Clone)]
+pub struct Backup {
+    pub name: String,
+    pub timestamp: String,
+}
+
+impl Backup {
+    fn target(project: &Project) -> PathBuf {
+        Path::new(&project.remote_dir).join(&project.target_name)
+    }
+
+    pub fn path(&self, project: &Project) -> PathBuf {
+        Path::new(&project.remote_dir).join(&self.name)
+    }
+
+    /// 与部署文件一起备份的部署记录
+    fn manifest_path(&self, project: &Project) -> PathBuf {
+        let manifest = Manifest::path(project, Path::new(&project.remote_dir));
+        PathBuf::from(format!("{}.{}", manifest.to_string_lossy(), self.timestamp))
+    }
+
+    /// 上传前备份当前的部署文件，文件不存在或backup_count为0时不备份
+    pub fn create(ssh: &mut SshUtil, project: &Project, timestamp: &str) -> Result<Option<Backup>> {
+        let target = Backup::target(project);
+        if project.backup_count == 0 || project.target_name.is_empty() || !ssh.exists(&target)? {
+            return Ok(None);
+        }
+        let backup = Backup::new(project, timestamp);
+        ssh.exec(backup.create_cmd(project), &[])?;
+        Ok(Some(backup))
+    }
+
+    pub fn new(project: &Project, timestamp: &str) -> Backup {
+        Backup { name: format!("{}.{}", project.target_name, timestamp), timestamp: timestamp.to_string() }
+    }
+
+    /// 复制当前部署文件和部署记录为备份的命令，部署记录不存在时只备份部署文件
+    pub fn create_cmd(&self, project: &Project) -> String {
+        let manifest = quote(&Manifest::path(project, Path::new(&project.remote_dir)).to_string_lossy());
+        format!("cp -p {} {} && if [ -f {} ]; then cp -p {} {}; fi",
+                quote(&Backup::target(project).to_string_lossy()), quote(&self.path(project).to_string_lossy()),
+                manifest, manifest, quote(&self.manifest_path(project).to_string_lossy()))
+    }
+
+    /// 服务器上的全部备份，按时间从新到旧排列
+    pub fn list(ssh: &mut SshUtil, project: &Project) -> Result<Vec<Backup>> {
+        if project.target_name.is_empty() {
+            return Ok(vec![]);
+        }
+        // target_name可以包含子目录，备份与部署文件在同一目录
+        let target = Path::new(&project.target_name);
+        let file_name = target.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let parent = target.parent().unwrap_or_else(|| Path::new(""));
+        let reg = Regex::new(&format!(r"^{}\.(\d{{8}}-\d{{6}})$", regex::escape(&file_name)))?;
+        let mut backups: Vec<Backup> = ssh.list_dir(&Path::new(&project.remote_dir).join(parent))?.into_iter()
+            .filter_map(|name| {
+                let timestamp = reg.captures(&name)?.get(1)?.as_str().to_string();
+                Some(Backup { name: parent.join(&name).to_string_lossy().to_string(), timestamp })
+            })
+            .collect();
+        backups.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+        Ok(backups)
+    }
+
+    /// 只保留最近的backup_count个备份
+    pub fn prune(ssh: &mut SshUtil, project: &Project) -> Result<()> {
+        for backup in Backup::list(ssh, project)?.iter().skip(project.backup_count) {
+            ssh.logger.line(&format!("删除备份：{}", backup.name));
+            ssh.remove_file(&backup.path(project))?;
+            ssh.exec(format!("rm -f {}", quote(&backup.manifest_path(project).to_string_lossy())), &[])?;
+        }
+        Ok(())
+    }
+
+    /// 使用备份覆盖当前的部署文件和部署记录，备份中没有部署记录时删除当前的部署记录，避免status显示已被覆盖的构建
+    pub fn restore(&self, ssh: &mut SshUtil, project: &Project) -> Result<()> {
+        let target = Backup::target(project);
+        let manifest = quote(&Manifest::path(project, Path::new(&project.remote_dir)).to_string_lossy());
+        let manifest_backup = quote(&self.manifest_path(project).to_string_lossy());
+        let cmd = format!("cp -p {} {} && if [ -f {} ]; then cp -p {} {}; else rm -f {}; fi",
+                          quote(&self.path(project).to_string_lossy()), quote(&target.to_string_lossy()),
+                          manifest_backup, manifest_backup, manifest, manifest);
+        ssh.exec(cmd, &[])
+    }
+}
@@ -0,0 +1,137 @@
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use chrono::Local;
+
+use crate::artifact::UploadFile;
+use crate::config::Project;
+use crate::git::GitInfo;
+use crate::utils::xdg_dir;
+
+/// 构建信息文件名称
+const BUILD_FILE: &str = "build.toml";
+
+/// 构建ID使用的提交或内容哈希的长度
+const ID_LEN: usize = 12;
+
+/// 本地缓存的构建结果，保存在 `缓存目录/项目/配置/构建ID/`，部署文件按相对于source_dir的路径保存在files目录中
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Build {
+    pub project: String,
+    pub profile: String,
+    pub id: String,
+    pub created: String,
+    pub files: usize,
+    pub size: u64,
+    /// 构建时的git提交和分支
+    #[serde(default)]
+    pub git: Option<GitInfo>,
+}
+
+impl Build {
+    fn path(&self) -> PathBuf {
+        BuildCache::root().join(&self.project).join(&self.profile).join(&self.id)
+    }
+
+    /// 缓存的部署文件目录，复用构建时作为source_dir查找部署文件
+    pub fn source_dir(&self) -> PathBuf {
+        self.path().join("files")
+    }
+}
+
+pub struct BuildCache;
+
+impl BuildCache {
+    /// 缓存目录，优先使用 `$XDG_CACHE_HOME/deploy_tool/builds`，其次为 `~/.cache/deploy_tool/builds`
+    pub fn root() -> PathBuf {
+        xdg_dir("XDG_CACHE_HOME", ".cache").join("builds")
+    }
+
+    /// source_dir为git仓库时使用当前提交，有未提交的修改时加上 `-dirty`，否则使用部署文件的内容哈希
+    pub fn build_id(files: &[UploadFile], git: Option<&GitInfo>) -> String {
+        if let Some(git) = git {
+            return git.describe();
+        }
+        UploadFile::checksum(files)[..ID_LEN].to_string()
+    }
+
+    /// 复制部署文件到缓存目录，相同构建ID的缓存会被替换
+    pub fn store(project: &Project, profile: &str, files: &[UploadFile], git: Option<&GitInfo>) -> Result<Build> {
+        let build = Build {
+            project: project.name.clone(),
+            profile: profile.to_string(),
+            id: BuildCache::build_id(files, git),
+            created: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            files: files.len(),
+            size: files.iter().map(|x| x.size).sum(),
+            git: git.cloned(),
+        };
+        let path = build.path();
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+        let source_dir = Path::new(&project.source_dir);
+        for file in files {
+            let dest = build.source_dir().join(file.local.strip_prefix(source_dir)?);
+            fs::create_dir_all(dest.parent().unwrap())?;
+            fs::copy(&file.local, &dest)?;
+            let modified = fs::metadata(&file.local)?.modified()?;
+            File::options().write(true).open(&dest)?.set_modified(modified)?;
+        }
+        fs::write(path.join(BUILD_FILE), toml::to_string(&build)?)?;
+        Ok(build)
+    }
+
+    /// 缓存的全部构建，project不为空时只列出该项目，按时间从新到旧排列
+    pub fn list(project: Option<&str>) -> Result<Vec<Build>> {
+        let mut builds = vec![];
+        let root = BuildCache::root();
+        if !root.is_dir() {
+            return Ok(builds);
+        }
+        for project_dir in fs::read_dir(root)? {
+            let project_dir = project_dir?.path();
+            if project.map(|x| !project_dir.ends_with(x)).unwrap_or(false) || !project_dir.is_dir() {
+                continue;
+            }
+            for profile_dir in fs::read_dir(project_dir)? {
+                let profile_dir = profile_dir?.path();
+                if !profile_dir.is_dir() {
+                    continue;
+                }
+                for build_dir in fs::read_dir(profile_dir)? {
+                    let build_file = build_dir?.path().join(BUILD_FILE);
+                    if let Ok(content) = fs::read_to_string(&build_file) {
+                        builds.push(toml::from_str::<Build>(&content)?);
+                    }
+                }
+            }
+        }
+        builds.sort_by(|a, b| b.created.cmp(&a.created));
+        Ok(builds)
+    }
+
+    /// 查找项目和配置对应的构建，id为latest时使用最新的构建，否则按构建ID前缀匹配
+    pub fn find(project: &str, profile: &str, id: &str) -> Result<Build> {
+        let builds: Vec<Build> = BuildCache::list(Some(project))?.into_iter().filter(|x| x.profile == profile).collect();
+        if builds.is_empty() {
+            return Err(anyhow!("项目 {} 配置 {} 没有缓存的构建！", project, profile));
+        }
+        if id == "latest" {
+            return Ok(builds[0].clone());
+        }
+        if let Some(build) = builds.iter().find(|x| x.id == id) {
+            return Ok(build.clone());
+        }
+        let matched: Vec<&Build> = builds.iter().filter(|x| x.id.starts_with(id)).collect();
+        match matched.as_slice() {
+            [build] => Ok((*build).clone()),
+            [] => {
+                let ids: Vec<String> = builds.iter().map(|x| x.id.clone()).collect();
+                Err(anyhow!("构建 {} 不存在，可选构建：{}", id, ids.join(", ")))
+            }
+            _ => Err(anyhow!("构建ID {} 匹配到多个构建，请输入更长的构建ID", id)),
+        }
+    }
+}
@@ -1,135 +1,1642 @@
-use std::collections::HashMap;
+use std::env;
 use std::fs::OpenOptions;
 use std::io::Read;
+use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Result};
-use regex::Regex;
+use dialoguer::console::{style, Term};
+use indexmap::IndexMap;
 use toml::Value;
-use toml::value::Table;
 
-#[derive(Debug, Clone, Deserialize)]
+use crate::utils::{quote, xdg_dir};
+use crate::vars;
+
+/// 指定配置文件的环境变量
+pub const CONFIG_ENV: &str = "DEPLOY_TOOL_CONFIG";
+
+/// 配置文件查找顺序中的一个位置
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub path: PathBuf,
+    /// 选择该位置的原因
+    pub reason: String,
+    /// 命令行参数或环境变量指定的文件必须存在，不再继续查找
+    pub explicit: bool,
+    /// 是否合并同目录下conf.d中的配置文件，只有用户配置目录中的config.toml合并
+    pub conf_d: bool,
+}
+
+/// 按查找顺序选中的配置文件
+#[derive(Debug, Clone)]
+pub struct ConfigPath {
+    pub path: PathBuf,
+    pub reason: String,
+    conf_d: bool,
+}
+
+impl ConfigPath {
+    /// 查找顺序：--config、环境变量DEPLOY_TOOL_CONFIG、从当前目录向上查找deploy.toml、
+    /// `$XDG_CONFIG_HOME/deploy_tool/config.toml`、`~/.config/deploy_tool/config.toml`
+    pub fn candidates(arg: Option<&str>) -> Vec<Candidate> {
+        let mut candidates = vec![];
+        if let Some(path) = arg {
+            candidates.push(Candidate { path: PathBuf::from(path), reason: "命令行参数 --config 指定".to_string(), explicit: true, conf_d: false });
+        }
+        if let Some(path) = env::var_os(CONFIG_ENV).filter(|x| !x.is_empty()) {
+            candidates.push(Candidate { path: PathBuf::from(path), reason: format!("环境变量 {} 指定", CONFIG_ENV), explicit: true, conf_d: false });
+        }
+        if let Ok(dir) = env::current_dir() {
+            for (index, dir) in dir.ancestors().enumerate() {
+                let reason = if index == 0 { "当前目录中的deploy.toml".to_string() } else { "从当前目录向上查找到的deploy.toml".to_string() };
+                candidates.push(Candidate { path: dir.join("deploy.toml"), reason, explicit: false, conf_d: false });
+            }
+        }
+        if env::var_os("XDG_CONFIG_HOME").filter(|x| !x.is_empty()).is_some() {
+            let path = xdg_dir("XDG_CONFIG_HOME", ".config").join("config.toml");
+            candidates.push(Candidate { path, reason: "$XDG_CONFIG_HOME 中的用户配置".to_string(), explicit: false, conf_d: true });
+        }
+        if let Some(home) = env::var_os("HOME").filter(|x| !x.is_empty()) {
+            let path = Path::new(&home).join(".config").join("deploy_tool").join("config.toml");
+            candidates.push(Candidate { path, reason: "~/.config 中的用户配置".to_string(), explicit: false, conf_d: true });
+        }
+        candidates
+    }
+
+    /// 按查找顺序返回第一个存在的配置文件，命令行参数或环境变量指定的文件不存在时返回错误
+    pub fn find(arg: Option<&str>) -> Result<ConfigPath> {
+        let candidates = ConfigPath::candidates(arg);
+        for candidate in &candidates {
+            if candidate.path.is_file() {
+                return Ok(ConfigPath { path: candidate.path.clone(), reason: candidate.reason.clone(), conf_d: candidate.conf_d });
+            }
+            if candidate.explicit {
+                return Err(anyhow!("{}的配置文件 {} 不存在", candidate.reason, candidate.path.display()));
+            }
+        }
+        let paths: Vec<String> = candidates.iter().map(|x| format!("  {}", x.path.display())).collect();
+        Err(anyhow!("未找到配置文件，已查找：\n{}", paths.join("\n")))
+    }
+
+    /// 用户配置目录中的conf.d，其中的 `*.toml` 按文件名顺序合并；
+    /// --config、环境变量指定的文件和deploy.toml不合并conf.d，需要时使用include
+    pub fn conf_dir(&self) -> Option<PathBuf> {
+        self.path.parent().filter(|_| self.conf_d).map(|x| x.join("conf.d"))
+    }
+
+    /// 配置文件同目录下存在但不会合并的conf.d
+    fn skipped_conf_dir(&self) -> Option<PathBuf> {
+        self.path.parent().filter(|_| !self.conf_d).map(|x| x.join("conf.d")).filter(|x| x.is_dir())
+    }
+
+    /// config path子命令，输出选中的配置文件、原因和查找顺序
+    pub fn print(arg: Option<&str>, term: &Term) -> Result<()> {
+        let found = ConfigPath::find(arg);
+        if let Ok(config) = &found {
+            term.write_line(&format!("配置文件：{}", config.path.display()))?;
+            term.write_line(&format!("原因：{}", config.reason))?;
+            if let Some(conf_dir) = config.conf_dir().filter(|x| x.is_dir()) {
+                term.write_line(&format!("合并目录：{}", conf_dir.display()))?;
+            }
+            if let Some(conf_dir) = config.skipped_conf_dir() {
+                let notice = format!("未合并目录：{} (只有用户配置目录中的conf.d会自动合并，需要时在配置文件中使用 include = ['conf.d/*.toml'])", conf_dir.display());
+                term.write_line(&style(notice).yellow().to_string())?;
+            }
+        }
+        term.write_line("查找顺序：")?;
+        let mut picked = false;
+        for candidate in ConfigPath::candidates(arg) {
+            let status = if picked {
+                "未检查"
+            } else if candidate.path.is_file() {
+                picked = true;
+                "已选择"
+            } else {
+                "不存在"
+            };
+            let line = format!("  {} ({}) {}", candidate.path.display(), candidate.reason, status);
+            term.write_line(&if status == "已选择" { style(line).bold().to_string() } else { line })?;
+            picked |= candidate.explicit;
+        }
+        found.map(|_| ())
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Config {
     pub servers: Vec<Server>,
     pub projects: Vec<Project>,
+    /// 全局变量，可在全部项目的命令中使用
+    pub vars: IndexMap<String, String>,
+    /// 服务器分组，成员为服务器或其他分组的名称
+    pub groups: IndexMap<String, Vec<String>>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Server {
+    #[serde(skip)]
     pub name: String,
     pub host: String,
+    #[serde(default = "Server::default_port")]
     pub port: i64,
     pub user: String,
+    #[serde(default)]
     pub password: String,
+    #[serde(default)]
     pub private_key: String,
+    #[serde(default)]
     pub identity_file: String,
+    /// 服务器变量，可在after命令中使用
+    #[serde(default)]
+    pub vars: IndexMap<String, String>,
+    /// 服务器标签，例如 `{ region = 'cn-south', role = 'web' }`，用于 `--tag` 选择服务器
+    #[serde(default)]
+    pub tags: IndexMap<String, String>,
+    /// 菜单和部署顺序，未配置时按配置文件中的顺序排在已配置项之后
+    #[serde(default)]
+    pub order: Option<i64>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Project {
+    #[serde(skip)]
     pub name: String,
     pub source_dir: String,
     pub remote_dir: String,
+    #[serde(default)]
     pub target_name: String,
-    pub before: HashMap<String, Vec<String>>,
-    pub after: HashMap<String, Vec<String>>,
+    /// 需要上传的文件、目录或通配符，未配置时上传target_name
+    #[serde(default)]
+    pub artifacts: Vec<Artifact>,
+    #[serde(default)]
+    pub before: IndexMap<String, Vec<Cmd>>,
+    #[serde(default)]
+    pub after: IndexMap<String, Vec<Cmd>>,
+    /// 项目中任意命令执行失败时都继续执行
+    #[serde(default)]
+    pub continue_on_error: bool,
+    /// 同时部署的服务器数量
+    #[serde(default)]
+    pub parallelism: Option<usize>,
+    /// 部署方式，in_place为直接覆盖部署文件，release为上传到版本目录后切换current链接
+    #[serde(default)]
+    pub layout: Layout,
+    /// release部署方式下保留的版本数量
+    #[serde(default = "Project::default_keep_releases")]
+    pub keep_releases: usize,
+    /// 部署完成后保留本地的target_name
+    #[serde(default)]
+    pub keep_artifact: bool,
+    /// 上传方式，scp直接写入目标文件，sftp先写入 `.part` 临时文件，支持断点续传，完成后重命名
+    #[serde(default)]
+    pub upload_method: UploadMethod,
+    /// 上传中断后的重试次数
+    #[serde(default = "Project::default_upload_retries")]
+    pub upload_retries: usize,
+    /// 增量同步，只上传目录和通配符部署文件中新增和修改的文件
+    #[serde(default)]
+    pub sync: Option<SyncOptions>,
+    /// 服务器上保留的备份数量，为0时不备份
+    #[serde(default = "Project::default_backup_count")]
+    pub backup_count: usize,
+    /// 分批滚动部署，未配置时一次部署全部服务器
+    #[serde(default)]
+    pub rolling: Option<Rolling>,
+    /// source_dir为git仓库时的构建前检查
+    #[serde(default)]
+    pub git: GitOptions,
+    /// 项目变量，可在before和after命令中使用
+    #[serde(default)]
+    pub vars: IndexMap<String, String>,
+    /// 按before和after的配置项名称配置的变量和服务器
+    #[serde(default)]
+    pub profiles: IndexMap<String, Profile>,
+    /// 允许部署的服务器或分组，未配置时可以部署到全部服务器
+    #[serde(default)]
+    pub servers: Vec<String>,
+    #[serde(default)]
+    pub order: Option<i64>,
+    /// 按服务器覆盖后的项目配置，来自 `[project.名称.server.服务器名称]`
+    #[serde(skip)]
+    pub overrides: IndexMap<String, Project>,
+    /// 按服务器覆盖的配置项名称，项目本身为空
+    #[serde(skip)]
+    pub overridden: Vec<String>,
 }
 
+/// 部署命令，配置中可以直接写命令字符串，也可以写成 `{ cmd = 'ls', continue_on_error = true }`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "CmdConfig")]
+pub struct Cmd {
+    pub cmd: String,
+    /// 命令执行失败时继续执行后续命令
+    pub continue_on_error: bool,
+    /// 除0以外视为执行成功的退出码
+    pub allowed_exit_codes: Vec<i32>,
+}
 
-impl Config {
-    fn replace_with_reg(reg: &Regex, value: String, replace: String) -> String {
-        reg.replace_all(&*value, replace.as_str()).to_string()
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CmdConfig {
+    Simple(String),
+    Full {
+        cmd: String,
+        #[serde(default)]
+        continue_on_error: bool,
+        #[serde(default)]
+        allowed_exit_codes: Vec<i32>,
+    },
+}
+
+impl From<CmdConfig> for Cmd {
+    fn from(config: CmdConfig) -> Cmd {
+        match config {
+            CmdConfig::Simple(cmd) => Cmd { cmd, continue_on_error: false, allowed_exit_codes: vec![] },
+            CmdConfig::Full { cmd, continue_on_error, allowed_exit_codes } => Cmd { cmd, continue_on_error, allowed_exit_codes },
+        }
+    }
+}
+
+/// 部署文件，配置中可以直接写路径，也可以写成 `{ path = 'lib/*.jar', dest = 'lib' }`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "ArtifactConfig")]
+pub struct Artifact {
+    /// 相对于source_dir的文件、目录或通配符
+    pub path: String,
+    /// 相对于部署目录的上传位置，默认为部署目录
+    pub dest: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ArtifactConfig {
+    Simple(String),
+    Full {
+        path: String,
+        #[serde(default)]
+        dest: Option<String>,
+    },
+}
+
+impl From<ArtifactConfig> for Artifact {
+    fn from(config: ArtifactConfig) -> Artifact {
+        match config {
+            ArtifactConfig::Simple(path) => Artifact { path, dest: None },
+            ArtifactConfig::Full { path, dest } => Artifact { path, dest },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadMethod {
+    #[default]
+    Scp,
+    Sftp,
+}
+
+/// 增量同步配置
+#[derive(Debug, Clone, Deserialize)]
+pub struct SyncOptions {
+    #[serde(default)]
+    pub compare: Compare,
+    /// 删除服务器上本地已不存在的文件
+    #[serde(default)]
+    pub delete: bool,
+}
+
+/// 比较本地和服务器上文件的方式，mtime比较大小和修改时间，hash在服务器上执行sha256sum比较内容
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compare {
+    #[default]
+    Mtime,
+    Hash,
+}
+
+/// 配置项(profile)，名称与before和after中的配置项名称对应
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Profile {
+    #[serde(default)]
+    pub vars: IndexMap<String, String>,
+    /// 该配置项允许部署的服务器或分组
+    #[serde(default)]
+    pub servers: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct GitOptions {
+    /// 工作区有未提交的修改时的处理方式
+    #[serde(default)]
+    pub dirty: Dirty,
+}
+
+/// warn提示后继续部署，refuse中止部署，allow不提示
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Dirty {
+    #[default]
+    Warn,
+    Refuse,
+    Allow,
+}
+
+/// 分批滚动部署配置，batch_size和batch_percent二选一
+#[derive(Debug, Clone, Deserialize)]
+pub struct Rolling {
+    #[serde(default)]
+    pub batch_size: Option<usize>,
+    #[serde(default)]
+    pub batch_percent: Option<usize>,
+    /// 两批之间的间隔秒数
+    #[serde(default)]
+    pub pause: u64,
+    /// 每批部署完成后的健康检查，通过后才开始下一批
+    #[serde(default)]
+    pub health_check: Option<HealthCheck>,
+}
+
+/// 健康检查，在目标服务器上执行cmd或请求url，直到成功或超时
+#[derive(Debug, Clone, Deserialize)]
+pub struct HealthCheck {
+    #[serde(default)]
+    pub cmd: Option<String>,
+    #[serde(default)]
+    pub url: Option<String>,
+    /// 超时秒数
+    #[serde(default = "HealthCheck::default_timeout")]
+    pub timeout: u64,
+    /// 重试间隔秒数
+    #[serde(default = "HealthCheck::default_interval")]
+    pub interval: u64,
+}
+
+impl Rolling {
+    /// 每批部署的服务器数量，至少为1
+    pub fn batch_len(&self, total: usize) -> usize {
+        let len = match (self.batch_size, self.batch_percent) {
+            (Some(size), _) => size,
+            (None, Some(percent)) => (total * percent).div_ceil(100),
+            (None, None) => total,
+        };
+        len.max(1)
+    }
+}
+
+impl HealthCheck {
+    fn default_timeout() -> u64 {
+        60
+    }
+
+    fn default_interval() -> u64 {
+        5
+    }
+
+    /// 在目标服务器上执行的检查命令，url通过curl请求
+    pub fn command(&self) -> String {
+        match (&self.cmd, &self.url) {
+            (Some(cmd), _) => cmd.clone(),
+            (None, Some(url)) => format!("curl -fsS -o /dev/null --max-time {} {}", self.interval.max(1), quote(url)),
+            (None, None) => "true".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Layout {
+    #[default]
+    InPlace,
+    Release,
+}
+
+impl Project {
+    /// 服务器使用的项目配置，没有按服务器覆盖时为项目本身
+    pub fn for_server(&self, server: &str) -> &Project {
+        self.overrides.get(server).unwrap_or(self)
+    }
+
+    /// 按服务器覆盖项目配置，after按配置项名称覆盖，其他配置项整体替换
+    fn merge(project: &Value, server: &Value) -> Value {
+        let mut merged = project.as_table().cloned().unwrap_or_default();
+        merged.remove("server");
+        for (key, value) in server.as_table().into_iter().flatten() {
+            match (merged.get_mut(key), value) {
+                (Some(Value::Table(base)), Value::Table(table)) if key == "after" => {
+                    base.extend(table.iter().map(|(name, cmds)| (name.clone(), cmds.clone())));
+                }
+                _ => {
+                    merged.insert(key.clone(), value.clone());
+                }
+            }
+        }
+        Value::Table(merged)
+    }
+
+    /// 需要上传的部署文件，未配置artifacts时为target_name
+    pub fn deploy_artifacts(&self) -> Vec<Artifact> {
+        match self.artifacts.is_empty() {
+            // target_name可以包含子目录，上传到remote_dir中的同一相对路径，与备份的路径一致
+            true => {
+                let dest = Path::new(&self.target_name).parent()
+                    .map(|x| x.to_string_lossy().to_string())
+                    .filter(|x| !x.is_empty());
+                vec![Artifact { path: self.target_name.clone(), dest }]
+            }
+            false => self.artifacts.clone()
+        }
+    }
+
+    fn default_backup_count() -> usize {
+        5
+    }
+
+    fn default_keep_releases() -> usize {
+        5
+    }
+
+    fn default_upload_retries() -> usize {
+        3
+    }
+}
+
+impl Server {
+    fn default_port() -> i64 {
+        22
+    }
+
+    /// tag为 `key=value` 时要求标签值相同，为 `key` 时只要求有该标签
+    pub fn has_tag(&self, tag: &str) -> bool {
+        match tag.split_once('=') {
+            Some((key, value)) => self.tags.get(key.trim()).map(|x| x == value.trim()).unwrap_or(false),
+            None => self.tags.contains_key(tag.trim())
+        }
+    }
+}
+
+/// 配置项的值类型
+#[derive(Clone, Copy)]
+enum Kind {
+    Str,
+    Int,
+    Bool,
+    IntList,
+    Table(&'static Schema),
+    /// 数组，每项为字符串或符合schema的表
+    List(&'static Schema),
+    /// 可选值固定的字符串
+    Enum(&'static [&'static str]),
+    /// 配置名称到命令列表的映射，例如before和after
+    Cmds,
+    /// 变量名称到字符串值的映射
+    Vars,
+    /// 名称到字符串值的映射，例如tags
+    StrMap,
+    /// 服务器或分组名称数组
+    Servers,
+    /// 名称到符合schema的表的映射，例如profiles
+    Map(&'static Schema),
+}
+
+/// (配置项路径, before或after, 命令表, 按服务器覆盖时的服务器名称)
+type CommandSection<'a> = (Vec<String>, &'a str, Option<&'a Value>, Option<&'a String>);
+
+/// (配置项名称, 值类型, 是否必填)
+type Schema = [(&'static str, Kind, bool)];
+
+const SERVER_KEYS: &Schema = &[
+    ("host", Kind::Str, true),
+    ("port", Kind::Int, false),
+    ("user", Kind::Str, true),
+    ("password", Kind::Str, false),
+    ("private_key", Kind::Str, false),
+    ("identity_file", Kind::Str, false),
+    ("vars", Kind::Vars, false),
+    ("tags", Kind::StrMap, false),
+    ("order", Kind::Int, false),
+];
+
+const PROJECT_KEYS: &Schema = &[
+    ("source_dir", Kind::Str, true),
+    ("remote_dir", Kind::Str, true),
+    ("target_name", Kind::Str, false),
+    ("artifacts", Kind::List(ARTIFACT_KEYS), false),
+    ("before", Kind::Cmds, false),
+    ("after", Kind::Cmds, false),
+    ("continue_on_error", Kind::Bool, false),
+    ("parallelism", Kind::Int, false),
+    ("layout", Kind::Enum(&["in_place", "release"]), false),
+    ("keep_releases", Kind::Int, false),
+    ("keep_artifact", Kind::Bool, false),
+    ("upload_method", Kind::Enum(&["scp", "sftp"]), false),
+    ("upload_retries", Kind::Int, false),
+    ("sync", Kind::Table(SYNC_KEYS), false),
+    ("backup_count", Kind::Int, false),
+    ("rolling", Kind::Table(ROLLING_KEYS), false),
+    ("git", Kind::Table(GIT_KEYS), false),
+    ("vars", Kind::Vars, false),
+    ("profiles", Kind::Map(PROFILE_KEYS), false),
+    ("servers", Kind::Servers, false),
+    ("server", Kind::Map(OVERRIDE_KEYS), false),
+    ("order", Kind::Int, false),
+];
+
+/// 可以按服务器覆盖的项目配置项，全部服务器上传同一次构建的部署文件，不能覆盖artifacts，
+/// 服务器的变量配置在服务器的vars中，不能覆盖项目的vars
+const OVERRIDE_KEYS: &Schema = &[
+    ("remote_dir", Kind::Str, false),
+    ("target_name", Kind::Str, false),
+    ("after", Kind::Cmds, false),
+    ("continue_on_error", Kind::Bool, false),
+    ("layout", Kind::Enum(&["in_place", "release"]), false),
+    ("keep_releases", Kind::Int, false),
+    ("upload_method", Kind::Enum(&["scp", "sftp"]), false),
+    ("upload_retries", Kind::Int, false),
+    ("sync", Kind::Table(SYNC_KEYS), false),
+    ("backup_count", Kind::Int, false),
+];
+
+const PROFILE_KEYS: &Schema = &[
+    ("vars", Kind::Vars, false),
+    ("servers", Kind::Servers, false),
+];
+
+const GIT_KEYS: &Schema = &[
+    ("dirty", Kind::Enum(&["warn", "refuse", "allow"]), false),
+];
+
+const SYNC_KEYS: &Schema = &[
+    ("compare", Kind::Enum(&["mtime", "hash"]), false),
+    ("delete", Kind::Bool, false),
+];
+
+const ROLLING_KEYS: &Schema = &[
+    ("batch_size", Kind::Int, false),
+    ("batch_percent", Kind::Int, false),
+    ("pause", Kind::Int, false),
+    ("health_check", Kind::Table(HEALTH_CHECK_KEYS), false),
+];
+
+const HEALTH_CHECK_KEYS: &Schema = &[
+    ("cmd", Kind::Str, false),
+    ("url", Kind::Str, false),
+    ("timeout", Kind::Int, false),
+    ("interval", Kind::Int, false),
+];
+
+const ARTIFACT_KEYS: &Schema = &[
+    ("path", Kind::Str, true),
+    ("dest", Kind::Str, false),
+];
+
+const CMD_KEYS: &Schema = &[
+    ("cmd", Kind::Str, true),
+    ("continue_on_error", Kind::Bool, false),
+    ("allowed_exit_codes", Kind::IntList, false),
+];
+
+/// 合并前的单个配置文件
+struct Source {
+    path: String,
+    text: String,
+}
+
+/// 按顺序合并的配置文件，origins记录每个配置项(例如 `server.a`)所在的文件
+#[derive(Default)]
+struct Sources {
+    files: Vec<Source>,
+    origins: IndexMap<String, usize>,
+    value: toml::value::Table,
+}
+
+/// 配置文件顶层的配置表，按名称合并，表中的每一项只能在一个文件中定义
+const MERGED_SECTIONS: &[&str] = &["server", "project", "vars", "groups"];
+
+impl Sources {
+    /// 读取配置文件并合并，再按顺序读取include匹配的文件，已读取的文件不重复读取
+    fn load(&mut self, path: &Path) -> Result<()> {
+        let canonical = path.canonicalize().map_err(|err| anyhow!("{}: {}", path.display(), err))?;
+        if self.files.iter().any(|x| Path::new(&x.path).canonicalize().ok().as_ref() == Some(&canonical)) {
+            return Ok(());
+        }
+        let mut text = String::new();
+        OpenOptions::new().read(true).open(path)
+            .and_then(|mut fs| fs.read_to_string(&mut text))
+            .map_err(|err| anyhow!("{}: {}", path.display(), err))?;
+        let mut table = match text.parse::<Value>() {
+            Ok(Value::Table(table)) => table,
+            Ok(_) => toml::value::Table::new(),
+            Err(err) => return Err(anyhow!("{}: {}", path.display(), err))
+        };
+        let include = table.remove("include");
+        let index = self.files.len();
+        self.files.push(Source { path: path.to_string_lossy().to_string(), text });
+        for (key, value) in table {
+            match value {
+                Value::Table(items) if MERGED_SECTIONS.contains(&key.as_str()) => {
+                    let section = self.value.entry(key.clone()).or_insert_with(|| Value::Table(Default::default()));
+                    let section = match section.as_table_mut() {
+                        Some(section) => section,
+                        None => return Err(self.duplicate(&key, index))
+                    };
+                    for (name, item) in items {
+                        let full = format!("{}.{}", key, name);
+                        if let Some(origin) = self.origins.get(&full) {
+                            return Err(anyhow!("{} 同时在 {} 和 {} 中定义", full, self.files[*origin].path, self.files[index].path));
+                        }
+                        self.origins.insert(full, index);
+                        section.insert(name, item);
+                    }
+                    self.origins.entry(key).or_insert(index);
+                }
+                value => {
+                    if self.value.contains_key(&key) {
+                        return Err(self.duplicate(&key, index));
+                    }
+                    self.origins.insert(key.clone(), index);
+                    self.value.insert(key, value);
+                }
+            }
+        }
+        let patterns = match include {
+            None => vec![],
+            Some(Value::Array(array)) if array.iter().all(|x| x.is_str()) => {
+                array.iter().filter_map(|x| x.as_str()).map(|x| x.to_string()).collect()
+            }
+            Some(_) => return Err(anyhow!("{}: include 应为字符串数组，例如 include = [\"servers/*.toml\"]", path.display()))
+        };
+        let base = path.parent().unwrap_or_else(|| Path::new("."));
+        for pattern in patterns {
+            self.load_glob(&base.join(&pattern))?;
+        }
+        Ok(())
+    }
+
+    /// 读取通配符匹配的全部文件，按文件名排序，不是通配符时文件必须存在
+    fn load_glob(&mut self, pattern: &Path) -> Result<()> {
+        let pattern = pattern.to_string_lossy().to_string();
+        if !pattern.contains(['*', '?', '[']) {
+            return self.load(Path::new(&pattern));
+        }
+        let mut paths = vec![];
+        for entry in glob::glob(&pattern).map_err(|err| anyhow!("include {}: {}", pattern, err))? {
+            let path = entry?;
+            if path.is_file() {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        for path in paths {
+            self.load(&path)?;
+        }
+        Ok(())
+    }
+
+    fn duplicate(&self, key: &str, index: usize) -> anyhow::Error {
+        let origin = self.origins.get(key).copied().unwrap_or_default();
+        anyhow!("{} 同时在 {} 和 {} 中定义", key, self.files[origin].path, self.files[index].path)
+    }
+
+    /// 配置项所在的文件，按 `section.name` 和 `section` 查找，找不到时为第一个文件
+    fn source(&self, keys: &[&str]) -> &Source {
+        let index = keys.get(..2).and_then(|x| self.origins.get(&x.join(".")))
+            .or_else(|| keys.first().and_then(|x| self.origins.get(*x)))
+            .copied().unwrap_or_default();
+        &self.files[index]
+    }
+}
+
+/// 配置校验，一次收集全部错误并定位到配置文件中的行
+struct Validator<'a> {
+    sources: &'a Sources,
+    errors: Vec<String>,
+    /// 配置中的服务器和分组名称，用于检查引用
+    servers: Vec<String>,
+    groups: Vec<String>,
+}
+
+impl<'a> Validator<'a> {
+    fn new(sources: &'a Sources) -> Validator<'a> {
+        Validator { sources, errors: vec![], servers: vec![], groups: vec![] }
+    }
+
+    fn type_name(value: &Value) -> &'static str {
+        match value {
+            Value::String(_) => "字符串",
+            Value::Integer(_) => "整数",
+            Value::Float(_) => "浮点数",
+            Value::Boolean(_) => "布尔值",
+            Value::Datetime(_) => "日期",
+            Value::Array(_) => "数组",
+            Value::Table(_) => "表",
+        }
+    }
+
+    /// 解析表头，例如 `[project.back.before]` 解析为 ["project", "back", "before"]
+    fn parse_keys(text: &str) -> Vec<String> {
+        text.split('.').map(|x| x.trim().trim_matches(|c| c == '"' || c == '\'').to_string()).collect()
+    }
+
+    /// 查找配置项所在行，找不到配置项时返回最近的上级表头所在行，
+    /// 只以子表形式出现时(例如 `[servers.a]` 中的servers)返回第一个子表的表头所在行
+    fn locate(&self, keys: &[&str]) -> Option<usize> {
+        let mut header: Vec<String> = vec![];
+        let mut table_line = None;
+        let mut child_line = None;
+        let mut table_depth = 0;
+        for (index, line) in self.sources.source(keys).text.lines().enumerate() {
+            let line = line.trim();
+            if line.starts_with('[') {
+                let name = line.trim_start_matches('[').split(']').next().unwrap_or("");
+                header = Validator::parse_keys(name);
+                if header.len() <= keys.len() && header.len() > table_depth && header.iter().zip(keys).all(|(a, b)| a == b) {
+                    table_line = Some(index + 1);
+                    table_depth = header.len();
+                } else if header.len() > keys.len() && child_line.is_none() && keys.iter().zip(&header).all(|(a, b)| a == b) {
+                    child_line = Some(index + 1);
+                }
+            } else if let Some(pos) = line.find('=') {
+                if line.starts_with('#') {
+                    continue;
+                }
+                let mut full = header.clone();
+                full.extend(Validator::parse_keys(&line[..pos]));
+                if full.len() <= keys.len() && full.len() > table_depth && full.iter().zip(keys).all(|(a, b)| a == b) {
+                    return Some(index + 1);
+                }
+            }
+        }
+        table_line.or(child_line)
+    }
+
+    fn child(keys: &[String], key: &str) -> Vec<String> {
+        let mut keys = keys.to_vec();
+        keys.push(key.to_string());
+        keys
+    }
+
+    /// 错误信息中的配置项路径，例如 `project.back.before.dev[1]`，数组下标以 `[n]` 形式保存
+    fn error(&mut self, keys: &[String], message: String) {
+        let mut path = String::new();
+        for key in keys {
+            if !path.is_empty() && !key.starts_with('[') {
+                path.push('.');
+            }
+            path.push_str(key);
+        }
+        let locate_keys: Vec<&str> = keys.iter().filter(|x| !x.starts_with('[')).map(|x| x.as_str()).collect();
+        let file = &self.sources.source(&locate_keys).path;
+        let error = match self.locate(&locate_keys) {
+            Some(line) => format!("{}:{}: {}: {}", file, line, path, message),
+            None => format!("{}: {}: {}", file, path, message)
+        };
+        self.errors.push(error);
+    }
+
+    fn check_list(&mut self, keys: &[String], array: &[Value], schema: &Schema) {
+        for (index, item) in array.iter().enumerate() {
+            let keys = Validator::child(keys, &format!("[{}]", index));
+            match item {
+                Value::String(_) => {}
+                Value::Table(table) => self.check_table(&keys, table, schema),
+                _ => self.error(&keys, format!("应为字符串或表，实际为{}", Validator::type_name(item)))
+            }
+        }
+    }
+
+    fn check_cmds(&mut self, keys: &[String], table: &toml::value::Table) {
+        for (name, cmds) in table {
+            let keys = Validator::child(keys, name);
+            match cmds.as_array() {
+                Some(array) => self.check_list(&keys, array, CMD_KEYS),
+                None => self.error(&keys, format!("应为命令数组，实际为{}", Validator::type_name(cmds)))
+            }
+        }
     }
 
-    fn get_table(value: &Value, key: String) -> Option<&Table> {
-        match value.get(key) {
-            Some(val) => Some(val.as_table().unwrap()),
-            None => None
+    fn check_vars(&mut self, keys: &[String], table: &toml::value::Table) {
+        for (name, value) in table {
+            let keys = Validator::child(keys, name);
+            if let Err(err) = vars::check_name(name) {
+                self.error(&keys, err.to_string());
+            } else if !value.is_str() {
+                self.error(&keys, format!("应为字符串，实际为{}", Validator::type_name(value)));
+            }
         }
     }
 
-    fn get_str(value: &Value, key: &str) -> String {
-        match value.get(key) {
-            Some(val) => val.as_str().unwrap().to_string(),
-            None => "".to_string()
+    /// 服务器或分组名称数组，名称必须存在
+    fn check_members(&mut self, keys: &[String], array: &[Value]) {
+        for (index, member) in array.iter().enumerate() {
+            let keys = Validator::child(keys, &format!("[{}]", index));
+            match member.as_str() {
+                Some(member) if !self.servers.iter().any(|x| x == member) && !self.groups.iter().any(|x| x == member) => {
+                    self.error(&keys, format!("服务器或分组 {} 不存在", member))
+                }
+                Some(_) => {}
+                None => self.error(&keys, format!("应为字符串，实际为{}", Validator::type_name(member)))
+            }
         }
     }
 
-    fn get_int(value: &Value, key: &str) -> i64 {
-        match value.get(key) {
-            Some(val) => val.as_integer().unwrap(),
-            None => 0
+    /// 分组中的成员名称，不是字符串数组时返回空
+    fn group_members(groups: &toml::value::Table, name: &str) -> Vec<String> {
+        groups.get(name).and_then(|x| x.as_array())
+            .map(|x| x.iter().filter_map(|member| member.as_str().map(|m| m.to_string())).collect())
+            .unwrap_or_default()
+    }
+
+    /// 服务器或分组名称展开后的服务器名称，与 `Config::expand` 相同，但分组循环引用时不会死循环
+    fn expand(groups: &toml::value::Table, names: &[String], servers: &mut Vec<String>) {
+        for name in names {
+            if groups.contains_key(name) {
+                if !servers.contains(name) {
+                    servers.push(name.clone());
+                    Validator::expand(groups, &Validator::group_members(groups, name), servers);
+                }
+            } else if !servers.contains(name) {
+                servers.push(name.clone());
+            }
         }
     }
 
-    fn get_map(value: &Value, key: &str) -> HashMap<String, Vec<String>> {
-        let target_name_reg = Regex::new(r"(\{target_name\})").unwrap();
-        let remote_dir_reg = Regex::new(r"(\{remote_dir\})").unwrap();
-        let source_dir_reg = Regex::new(r"(\{source_dir\})").unwrap();
+    /// 与 `Config::allowed_servers` 相同，项目和配置项的servers都配置时取交集，都未配置时返回None
+    fn allowed_servers(root: &Value, project: &Value, profile: &str) -> Option<Vec<String>> {
+        let groups = root.get("groups").and_then(|x| x.as_table()).cloned().unwrap_or_default();
+        let profile = project.get("profiles").and_then(|x| x.get(profile));
+        let mut allowed: Option<Vec<String>> = None;
+        for value in [project.get("servers"), profile.and_then(|x| x.get("servers"))] {
+            let names: Vec<String> = value.and_then(|x| x.as_array()).into_iter().flatten()
+                .filter_map(|x| x.as_str().map(|x| x.to_string()))
+                .collect();
+            if names.is_empty() {
+                continue;
+            }
+            let mut servers = vec![];
+            Validator::expand(&groups, &names, &mut servers);
+            servers.retain(|x| !groups.contains_key(x));
+            allowed = Some(match allowed {
+                Some(allowed) => allowed.into_iter().filter(|x| servers.contains(x)).collect(),
+                None => servers
+            });
+        }
+        allowed
+    }
 
-        let source_dir = Config::get_str(value, "source_dir");
-        let remote_dir = Config::get_str(value, "remote_dir");
-        let target_name = Config::get_str(value, "target_name");
+    /// 从start出发经过current能回到start时返回循环路径
+    fn group_cycle(groups: &toml::value::Table, start: &str, path: &mut Vec<String>) -> Option<Vec<String>> {
+        let current = path.last().cloned().unwrap_or_else(|| start.to_string());
+        for member in Validator::group_members(groups, &current) {
+            if member == start {
+                let mut cycle = vec![start.to_string()];
+                cycle.extend(path.iter().cloned());
+                cycle.push(member);
+                return Some(cycle);
+            }
+            if groups.contains_key(&member) && !path.contains(&member) {
+                path.push(member);
+                if let Some(cycle) = Validator::group_cycle(groups, start, path) {
+                    return Some(cycle);
+                }
+                path.pop();
+            }
+        }
+        None
+    }
 
-        match value.get(key) {
-            Some(val) => {
-                let mut data = HashMap::new();
-                let table = val.as_table().unwrap();
-                for sub_key in table.keys() {
-                    let item = table.get(sub_key).unwrap().as_array().unwrap();
-                    let vec: Vec<String> = item.iter().map(|x| x.as_str().unwrap().to_string())
-                        .map(|x| Config::replace_with_reg(&target_name_reg, x.clone(), target_name.clone()))
-                        .map(|x| Config::replace_with_reg(&remote_dir_reg, x.clone(), remote_dir.clone()))
-                        .map(|x| Config::replace_with_reg(&source_dir_reg, x.clone(), source_dir.clone()))
-                        .collect();
-                    data.insert(sub_key.to_string(), vec);
+    /// 检查分组成员是否存在，分组名称不能与服务器重名，分组之间不能循环引用
+    fn check_groups(&mut self, root: &Value) {
+        let groups = match root.get("groups") {
+            Some(Value::Table(groups)) => groups,
+            Some(value) => {
+                self.error(&["groups".to_string()], format!("应为表，实际为{}", Validator::type_name(value)));
+                return;
+            }
+            None => return
+        };
+        let servers = self.servers.clone();
+        let mut cycles: Vec<Vec<String>> = vec![];
+        for (name, members) in groups {
+            let keys = vec!["groups".to_string(), name.clone()];
+            if servers.contains(name) {
+                self.error(&keys, format!("分组名称 {} 与服务器重名", name));
+            }
+            match members.as_array() {
+                Some(array) => {
+                    self.check_members(&keys, array);
+                }
+                None => self.error(&keys, format!("应为服务器名称数组，实际为{}", Validator::type_name(members)))
+            }
+            if let Some(cycle) = Validator::group_cycle(groups, name, &mut vec![]) {
+                let mut members = cycle[1..].to_vec();
+                members.sort();
+                if !cycles.contains(&members) {
+                    self.error(&keys, format!("分组循环引用：{}", cycle.join(" -> ")));
+                    cycles.push(members);
                 }
-                data
             }
-            None => HashMap::new()
         }
     }
 
-    pub fn read_config(path: String) -> Result<Config> {
-        match OpenOptions::new().read(true).open(path) {
-            Ok(mut fs) => {
-                let mut config_str = String::new();
-                fs.read_to_string(&mut config_str)?;
-                let value = config_str.parse::<toml::Value>()?;
-                let server = Config::get_table(&value, "server".to_string()).unwrap();
-                let project = Config::get_table(&value, "project".to_string()).unwrap();
-                let mut servers: Vec<Server> = vec![];
-                let mut projects: Vec<Project> = vec![];
+    /// 表中的变量名称
+    fn var_names(value: Option<&Value>) -> Vec<String> {
+        value.and_then(|x| x.as_table()).map(|x| x.keys().cloned().collect()).unwrap_or_default()
+    }
 
-                for key in server.keys() {
-                    let item = server.get(key).unwrap();
-                    servers.push(Server {
-                        name: key.to_string(),
-                        host: Config::get_str(&item, "host"),
-                        port: Config::get_int(&item, "port"),
-                        user: Config::get_str(&item, "user"),
-                        password: Config::get_str(&item, "password"),
-                        private_key: Config::get_str(&item, "private_key"),
-                        identity_file: Config::get_str(&item, "identity_file"),
-                    });
+    /// 检查before和after命令中的占位符，before在本地执行，不能使用服务器变量，
+    /// after中的服务器变量需要允许部署的全部服务器都配置，或者配置了同名的全局、项目或配置项变量，
+    /// 按服务器覆盖的after命令只需要该服务器配置，`{env.VAR}` 在部署时读取，加载配置时不检查是否已设置
+    fn check_placeholders(&mut self, root: &Value) {
+        let global = Validator::var_names(root.get("vars"));
+        let server_vars: IndexMap<String, Vec<String>> = root.get("server").and_then(|x| x.as_table())
+            .map(|x| x.iter().map(|(name, server)| (name.clone(), Validator::var_names(server.get("vars")))).collect())
+            .unwrap_or_default();
+        let projects = match root.get("project").and_then(|x| x.as_table()) {
+            Some(projects) => projects,
+            None => return
+        };
+        for (name, project) in projects {
+            let project_vars = Validator::var_names(project.get("vars"));
+            let mut sections: Vec<CommandSection> = vec![];
+            for section in ["before", "after"] {
+                let keys = vec!["project".to_string(), name.clone(), section.to_string()];
+                sections.push((keys, section, project.get(section), None));
+            }
+            for (server, table) in project.get("server").and_then(|x| x.as_table()).into_iter().flatten() {
+                let keys = vec!["project".to_string(), name.clone(), "server".to_string(), server.clone(), "after".to_string()];
+                sections.push((keys, "after", table.get("after"), Some(server)));
+            }
+            for (keys, section, cmd_map, server) in sections {
+                let cmd_map = match cmd_map.and_then(|x| x.as_table()) {
+                    Some(cmd_map) => cmd_map,
+                    None => continue
+                };
+                for (profile, cmds) in cmd_map {
+                    let profile_vars = Validator::var_names(project.get("profiles").and_then(|x| x.get(profile)).and_then(|x| x.get("vars")));
+                    let servers: Vec<&Vec<String>> = match server {
+                        Some(server) => server_vars.get(server).into_iter().collect(),
+                        None => match Validator::allowed_servers(root, project, profile) {
+                            Some(allowed) => allowed.iter().filter_map(|x| server_vars.get(x)).collect(),
+                            None => server_vars.values().collect()
+                        }
+                    };
+                    for (index, cmd) in cmds.as_array().map(|x| x.as_slice()).unwrap_or_default().iter().enumerate() {
+                        let text = match cmd {
+                            Value::Table(table) => table.get("cmd").and_then(|x| x.as_str()),
+                            _ => cmd.as_str()
+                        };
+                        let keys = Validator::child(&Validator::child(&keys, profile), &format!("[{}]", index));
+                        for placeholder in vars::placeholders(text.unwrap_or_default()) {
+                            let defined = vars::BUILTINS.contains(&placeholder.as_str())
+                                || [&global, &project_vars, &profile_vars].iter().any(|x| x.contains(&placeholder));
+                            let server_var = vars::SERVER_BUILTINS.contains(&placeholder.as_str())
+                                || (!servers.is_empty() && servers.iter().all(|x| x.contains(&placeholder)));
+                            // 环境变量在部署时替换，只检查实际部署的项目和配置项
+                            if placeholder.starts_with("env.") {
+                                continue;
+                            }
+                            if server_var && !defined && section == "before" {
+                                self.error(&keys, format!("before命令在本地执行，不能使用服务器变量 {{{}}}", placeholder));
+                            } else if !defined && !server_var {
+                                self.error(&keys, format!("未知的占位符 {{{}}}", placeholder));
+                            }
+                        }
+                    }
                 }
-                for key in project.keys() {
-                    let item = project.get(key).unwrap();
-                    let before_cmd = Config::get_map(&item, "before");
-                    let after_cmd = Config::get_map(&item, "after");
+            }
+        }
+    }
 
-                    projects.push(Project {
-                        name: key.to_string(),
-                        source_dir: Config::get_str(&item, "source_dir"),
-                        remote_dir: Config::get_str(&item, "remote_dir"),
-                        target_name: Config::get_str(&item, "target_name"),
-                        before: before_cmd,
-                        after: after_cmd,
-                    });
+    fn check_value(&mut self, keys: &[String], value: &Value, kind: Kind) {
+        match (kind, value) {
+            (Kind::Str, Value::String(_)) => {}
+            (Kind::Int, Value::Integer(_)) => {}
+            (Kind::Bool, Value::Boolean(_)) => {}
+            (Kind::IntList, Value::Array(array)) => {
+                for (index, item) in array.iter().enumerate() {
+                    let keys = Validator::child(keys, &format!("[{}]", index));
+                    match item.as_integer() {
+                        Some(code) if code < i32::MIN as i64 || code > i32::MAX as i64 => self.error(&keys, format!("退出码 {} 超出范围", code)),
+                        Some(_) => {}
+                        None => self.error(&keys, format!("应为整数，实际为{}", Validator::type_name(item)))
+                    }
                 }
+            }
+            (Kind::Enum(values), Value::String(value)) => {
+                if !values.contains(&value.as_str()) {
+                    self.error(keys, format!("不支持的值 {}，可选值：{}", value, values.join(", ")));
+                }
+            }
+            (Kind::Table(schema), Value::Table(table)) => self.check_table(keys, table, schema),
+            (Kind::List(schema), Value::Array(array)) => self.check_list(keys, array, schema),
+            (Kind::Cmds, Value::Table(table)) => self.check_cmds(keys, table),
+            (Kind::Vars, Value::Table(table)) => self.check_vars(keys, table),
+            (Kind::Servers, Value::Array(array)) => self.check_members(keys, array),
+            (Kind::StrMap, Value::Table(table)) => {
+                for (name, value) in table {
+                    if !value.is_str() {
+                        self.error(&Validator::child(keys, name), format!("应为字符串，实际为{}", Validator::type_name(value)));
+                    }
+                }
+            }
+            (Kind::Map(schema), Value::Table(table)) => {
+                for (name, item) in table {
+                    let keys = Validator::child(keys, name);
+                    match item.as_table() {
+                        Some(item) => self.check_table(&keys, item, schema),
+                        None => self.error(&keys, format!("应为表，实际为{}", Validator::type_name(item)))
+                    }
+                }
+            }
+            (Kind::Str, _) | (Kind::Enum(_), _) => self.error(keys, format!("应为字符串，实际为{}", Validator::type_name(value))),
+            (Kind::Int, _) => self.error(keys, format!("应为整数，实际为{}", Validator::type_name(value))),
+            (Kind::Bool, _) => self.error(keys, format!("应为布尔值，实际为{}", Validator::type_name(value))),
+            (Kind::Servers, _) => self.error(keys, format!("应为服务器名称数组，实际为{}", Validator::type_name(value))),
+            (Kind::List(_), _) => self.error(keys, format!("应为数组，实际为{}", Validator::type_name(value))),
+            (Kind::IntList, _) => self.error(keys, format!("应为整数数组，实际为{}", Validator::type_name(value))),
+            (Kind::Table(_), _) | (Kind::Cmds, _) | (Kind::Vars, _) | (Kind::StrMap, _) | (Kind::Map(_), _) => self.error(keys, format!("应为表，实际为{}", Validator::type_name(value))),
+        }
+    }
+
+    fn check_table(&mut self, keys: &[String], table: &toml::value::Table, schema: &Schema) {
+        for (key, kind, required) in schema {
+            match table.get(*key) {
+                Some(value) => self.check_value(&Validator::child(keys, key), value, *kind),
+                None if *required => self.error(keys, format!("缺少配置项 {}", key)),
+                None => {}
+            }
+        }
+        for key in table.keys() {
+            if !schema.iter().any(|(name, _, _)| name == key) {
+                self.error(&Validator::child(keys, key), "未知的配置项".to_string());
+            }
+        }
+    }
+
+    fn check_section(&mut self, root: &Value, section: &str, schema: &Schema) {
+        let keys = vec![section.to_string()];
+        match root.get(section) {
+            Some(Value::Table(table)) if !table.is_empty() => {
+                for (name, value) in table {
+                    let keys = Validator::child(&keys, name);
+                    match value.as_table() {
+                        Some(table) => self.check_table(&keys, table, schema),
+                        None => self.error(&keys, format!("应为表，实际为{}", Validator::type_name(value)))
+                    }
+                }
+            }
+            Some(Value::Table(_)) | None => self.errors.push(format!("{}: 缺少 [{}] 配置", self.sources.files[0].path, section)),
+            Some(value) => self.error(&keys, format!("应为表，实际为{}", Validator::type_name(value))),
+        }
+    }
+
+    fn check_rolling(&mut self, keys: &[String], rolling: &Value) {
+        let batch_size = rolling.get("batch_size").and_then(|x| x.as_integer());
+        let batch_percent = rolling.get("batch_percent").and_then(|x| x.as_integer());
+        match (batch_size, batch_percent) {
+            (Some(_), Some(_)) => self.error(keys, "batch_size和batch_percent只能配置一项".to_string()),
+            (Some(size), None) if size <= 0 => self.error(&Validator::child(keys, "batch_size"), format!("每批数量 {} 应大于0", size)),
+            (None, Some(percent)) if percent <= 0 || percent > 100 => {
+                self.error(&Validator::child(keys, "batch_percent"), format!("每批比例 {} 应在1到100之间", percent))
+            }
+            _ => {}
+        }
+        if let Some(pause) = rolling.get("pause").and_then(|x| x.as_integer()) {
+            if pause < 0 {
+                self.error(&Validator::child(keys, "pause"), format!("间隔秒数 {} 不能小于0", pause));
+            }
+        }
+        if let Some(health_check) = rolling.get("health_check") {
+            let keys = Validator::child(keys, "health_check");
+            match (health_check.get("cmd"), health_check.get("url")) {
+                (Some(_), Some(_)) => self.error(&keys, "cmd和url只能配置一项".to_string()),
+                (None, None) => self.error(&keys, "缺少配置项 cmd 或 url".to_string()),
+                _ => {}
+            }
+            for (key, name) in [("timeout", "超时秒数"), ("interval", "重试间隔秒数")] {
+                if let Some(value) = health_check.get(key).and_then(|x| x.as_integer()) {
+                    if value < 0 {
+                        self.error(&Validator::child(&keys, key), format!("{} {} 不能小于0", name, value));
+                    }
+                }
+            }
+        }
+    }
+
+    /// 检查项目或按服务器覆盖的配置项取值，table为配置文件中的表，merged为覆盖后的项目配置
+    fn check_project(&mut self, keys: &[String], table: &Value, merged: &Value) {
+        if let Some(parallelism) = table.get("parallelism").and_then(|x| x.as_integer()) {
+            if parallelism <= 0 {
+                self.error(&Validator::child(keys, "parallelism"), format!("并发数量 {} 应大于0", parallelism));
+            }
+        }
+        if let Some(backup_count) = table.get("backup_count").and_then(|x| x.as_integer()) {
+            if backup_count < 0 {
+                self.error(&Validator::child(keys, "backup_count"), format!("备份数量 {} 不能小于0", backup_count));
+            }
+        }
+        if let Some(upload_retries) = table.get("upload_retries").and_then(|x| x.as_integer()) {
+            if upload_retries < 0 {
+                self.error(&Validator::child(keys, "upload_retries"), format!("重试次数 {} 不能小于0", upload_retries));
+            }
+        }
+        if let Some(keep_releases) = table.get("keep_releases").and_then(|x| x.as_integer()) {
+            if keep_releases <= 0 {
+                self.error(&Validator::child(keys, "keep_releases"), format!("保留版本数量 {} 应大于0", keep_releases));
+            }
+        }
+        let changed = table.get("sync").is_some() || table.get("layout").is_some();
+        if changed && merged.get("sync").is_some() && merged.get("layout").and_then(|x| x.as_str()) == Some("release") {
+            self.error(&Validator::child(keys, "sync"), "release部署方式每次上传到新的版本目录，不支持增量同步".to_string());
+        }
+    }
+
+    fn check(&mut self, root: &Value) {
+        self.servers = Validator::var_names(root.get("server"));
+        self.groups = Validator::var_names(root.get("groups"));
+        for key in root.as_table().into_iter().flat_map(|x| x.keys()) {
+            if !MERGED_SECTIONS.contains(&key.as_str()) {
+                self.error(std::slice::from_ref(key), "未知的配置项".to_string());
+            }
+        }
+        self.check_section(root, "server", SERVER_KEYS);
+        self.check_section(root, "project", PROJECT_KEYS);
+        if let Some(vars) = root.get("vars") {
+            self.check_value(&["vars".to_string()], vars, Kind::Vars);
+        }
+        self.check_placeholders(root);
+        self.check_groups(root);
+        if let Some(servers) = root.get("server").and_then(|x| x.as_table()) {
+            for (name, server) in servers {
+                if let Some(port) = server.get("port").and_then(|x| x.as_integer()) {
+                    if port <= 0 || port > 65535 {
+                        let keys = vec!["server".to_string(), name.clone(), "port".to_string()];
+                        self.error(&keys, format!("端口 {} 超出范围", port));
+                    }
+                }
+            }
+        }
+        if let Some(projects) = root.get("project").and_then(|x| x.as_table()) {
+            for (name, project) in projects {
+                let has_artifacts = project.get("artifacts").and_then(|x| x.as_array()).map(|x| !x.is_empty()).unwrap_or(false);
+                let has_target = project.get("target_name").and_then(|x| x.as_str()).map(|x| !x.is_empty()).unwrap_or(false);
+                if project.is_table() && !has_artifacts && !has_target {
+                    let keys = vec!["project".to_string(), name.clone()];
+                    self.error(&keys, "缺少配置项 target_name 或 artifacts".to_string());
+                }
+                let keys = vec!["project".to_string(), name.clone()];
+                self.check_project(&keys, project, project);
+                for (server, table) in project.get("server").and_then(|x| x.as_table()).into_iter().flatten() {
+                    let keys = Validator::child(&Validator::child(&keys, "server"), server);
+                    if !self.servers.contains(server) {
+                        self.error(&keys, format!("服务器 {} 不存在", server));
+                    }
+                    let mut profiles = Validator::var_names(project.get("before"));
+                    profiles.extend(Validator::var_names(project.get("after")));
+                    for profile in Validator::var_names(table.get("after")) {
+                        if !profiles.is_empty() && !profiles.contains(&profile) {
+                            self.error(&Validator::child(&keys, "after"), format!("before和after中没有配置项 {}", profile));
+                        }
+                    }
+                    self.check_project(&keys, table, &Project::merge(project, table));
+                }
+                if let Some(profiles) = project.get("profiles").and_then(|x| x.as_table()) {
+                    let mut names = Validator::var_names(project.get("before"));
+                    names.extend(Validator::var_names(project.get("after")));
+                    for profile in profiles.keys() {
+                        if !names.is_empty() && !names.contains(profile) {
+                            let keys = vec!["project".to_string(), name.clone(), "profiles".to_string(), profile.clone()];
+                            self.error(&keys, format!("before和after中没有配置项 {}", profile));
+                        }
+                    }
+                }
+                if let Some(rolling) = project.get("rolling") {
+                    let keys = vec!["project".to_string(), name.clone(), "rolling".to_string()];
+                    self.check_rolling(&keys, rolling);
+                }
+            }
+        }
+    }
+}
+
+impl Config {
+    /// 分组展开后的服务器名称，嵌套的分组递归展开并去重，加载配置时已检查循环引用
+    pub fn group_servers(&self, group: &str) -> Vec<String> {
+        let mut servers = vec![];
+        self.expand_group(group, &mut servers);
+        servers
+    }
+
+    /// 项目和配置项允许部署的服务器名称，两者都配置时取交集，都未配置时返回None
+    pub fn allowed_servers(&self, project: &Project, profile: &str) -> Option<Vec<String>> {
+        let mut allowed: Option<Vec<String>> = None;
+        let profile_servers = project.profiles.get(profile).map(|x| x.servers.clone()).unwrap_or_default();
+        for names in [&project.servers, &profile_servers] {
+            if names.is_empty() {
+                continue;
+            }
+            let servers = self.expand(names);
+            allowed = Some(match allowed {
+                Some(allowed) => allowed.into_iter().filter(|x| servers.contains(x)).collect(),
+                None => servers
+            });
+        }
+        allowed
+    }
 
-                Ok(Config { servers, projects })
+    /// 服务器或分组名称展开后的服务器名称
+    pub fn expand(&self, names: &[String]) -> Vec<String> {
+        let mut servers = vec![];
+        for name in names {
+            if self.groups.contains_key(name) {
+                self.expand_group(name, &mut servers);
+            } else if !servers.contains(name) {
+                servers.push(name.clone());
             }
-            Err(err) => Err(anyhow!(err.to_string()))
         }
+        servers
+    }
+
+    fn expand_group(&self, group: &str, servers: &mut Vec<String>) {
+        for member in self.groups.get(group).into_iter().flatten() {
+            if self.groups.contains_key(member) {
+                self.expand_group(member, servers);
+            } else if !servers.contains(member) {
+                servers.push(member.clone());
+            }
+        }
+    }
+
+    /// 按配置文件中的顺序返回表中的各项
+    fn entries(value: &Value, section: &str) -> Vec<(String, Value)> {
+        match value.get(section).and_then(|x| x.as_table()) {
+            Some(table) => table.iter().map(|(name, item)| (name.clone(), item.clone())).collect(),
+            None => vec![]
+        }
+    }
+
+    fn convert<T: serde::de::DeserializeOwned>(path: &str, section: &str, name: &str, item: Value) -> Result<T> {
+        match item.try_into() {
+            Ok(value) => Ok(value),
+            Err(err) => Err(anyhow!("{}: {}.{}: {}", path, section, name, err))
+        }
+    }
+
+    /// 读取配置文件，合并include引用的文件和conf_dir中的 `*.toml` 文件
+    pub fn read_config(path: String, conf_dir: Option<PathBuf>) -> Result<Config> {
+        let mut sources = Sources::default();
+        sources.load(Path::new(&path))?;
+        if let Some(conf_dir) = conf_dir.filter(|x| x.is_dir()) {
+            sources.load_glob(&conf_dir.join("*.toml"))?;
+        }
+        let value = Value::Table(sources.value.clone());
+        let mut validator = Validator::new(&sources);
+        validator.check(&value);
+        if !validator.errors.is_empty() {
+            return Err(anyhow!("配置文件校验失败：\n{}", validator.errors.join("\n")));
+        }
+        let mut servers: Vec<Server> = vec![];
+        for (name, item) in Config::entries(&value, "server") {
+            let server: Server = Config::convert(&sources.source(&["server", &name]).path, "server", &name, item)?;
+            servers.push(Server { name, ..server });
+        }
+        let mut projects: Vec<Project> = vec![];
+        for (name, item) in Config::entries(&value, "project") {
+            let path = &sources.source(&["project", &name]).path;
+            let mut overrides = IndexMap::new();
+            for (server, table) in Config::entries(&item, "server") {
+                let merged = Project::merge(&item, &table);
+                let project: Project = Config::convert(path, "project", &format!("{}.server.{}", name, server), merged)?;
+                let overridden = Validator::var_names(Some(&table));
+                overrides.insert(server, Project { name: name.clone(), overridden, ..project });
+            }
+            let project: Project = Config::convert(path, "project", &name, item)?;
+            projects.push(Project { name, overrides, ..project });
+        }
+        servers.sort_by_key(|x| x.order.unwrap_or(i64::MAX));
+        projects.sort_by_key(|x| x.order.unwrap_or(i64::MAX));
+
+        let vars = match value.get("vars") {
+            Some(vars) => Config::convert(&sources.source(&["vars"]).path, "vars", "", vars.clone())?,
+            None => IndexMap::new()
+        };
+        let groups = match value.get("groups") {
+            Some(groups) => Config::convert(&sources.source(&["groups"]).path, "groups", "", groups.clone())?,
+            None => IndexMap::new()
+        };
+        Ok(Config { servers, projects, vars, groups })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 单个配置文件的合并结果，不读取磁盘
+    fn sources(text: &str) -> Sources {
+        let value = text.parse::<Value>().unwrap().as_table().cloned().unwrap();
+        let mut origins = IndexMap::new();
+        for key in value.keys() {
+            origins.insert(key.clone(), 0);
+        }
+        Sources { files: vec![Source { path: "config.toml".to_string(), text: text.to_string() }], origins, value }
+    }
+
+    fn errors(text: &str) -> Vec<String> {
+        let sources = sources(text);
+        let mut validator = Validator::new(&sources);
+        validator.check(&Value::Table(sources.value.clone()));
+        validator.errors
+    }
+
+    const VALID: &str = "
+[server.a]
+host = '127.0.0.1'
+user = 'root'
+
+[project.demo]
+source_dir = '/tmp'
+remote_dir = '/srv'
+target_name = 'app.jar'
+";
+
+    #[test]
+    fn valid_config_has_no_errors() {
+        assert!(errors(VALID).is_empty());
+    }
+
+    #[test]
+    fn locate_key_and_table_lines() {
+        let sources = sources(VALID);
+        let validator = Validator::new(&sources);
+        assert_eq!(validator.locate(&["server", "a", "user"]), Some(4));
+        assert_eq!(validator.locate(&["project", "demo"]), Some(6));
+        // 配置项不存在时返回上级表头所在行
+        assert_eq!(validator.locate(&["project", "demo", "layout"]), Some(6));
+        assert_eq!(validator.locate(&["groups"]), None);
+    }
+
+    #[test]
+    fn error_reports_file_line_and_path() {
+        let text = format!("{}layout = 'blue'\nbefore = {{ dev = [1] }}\n", VALID);
+        let errors = errors(&text);
+        assert_eq!(errors, vec![
+            "config.toml:11: project.demo.before.dev[0]: 应为字符串或表，实际为整数".to_string(),
+            "config.toml:10: project.demo.layout: 不支持的值 blue，可选值：in_place, release".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn unknown_keys_are_reported() {
+        let text = format!("{}\n[servers.b]\nhost = 'x'\n", VALID.replace("user = 'root'", "user = 'root'\nhots = 'x'"));
+        let errors = errors(&text);
+        assert!(errors.contains(&"config.toml:5: server.a.hots: 未知的配置项".to_string()), "{:?}", errors);
+        assert!(errors.contains(&"config.toml:12: servers: 未知的配置项".to_string()), "{:?}", errors);
+    }
+
+    #[test]
+    fn missing_required_keys() {
+        let errors = errors(&VALID.replace("host = '127.0.0.1'\n", ""));
+        assert_eq!(errors, vec!["config.toml:2: server.a: 缺少配置项 host".to_string()]);
+    }
+
+    #[test]
+    fn rolling_batch_len() {
+        let rolling = |batch_size, batch_percent| Rolling { batch_size, batch_percent, pause: 0, health_check: None };
+        assert_eq!(rolling(Some(2), None).batch_len(5), 2);
+        assert_eq!(rolling(None, Some(50)).batch_len(5), 3);
+        assert_eq!(rolling(None, Some(10)).batch_len(3), 1);
+        assert_eq!(rolling(None, None).batch_len(4), 4);
+        // 至少每批一台服务器
+        assert_eq!(rolling(Some(0), None).batch_len(4), 1);
+        assert_eq!(rolling(None, None).batch_len(0), 1);
+    }
+
+    fn groups(text: &str) -> toml::value::Table {
+        text.parse::<Value>().unwrap().as_table().cloned().unwrap()
+    }
+
+    #[test]
+    fn group_cycle_detection() {
+        let table = groups("a = ['b', 's1']\nb = ['c']\nc = ['a']\nd = ['s1']");
+        assert_eq!(Validator::group_cycle(&table, "a", &mut vec![]), Some(vec!["a", "b", "c", "a"].into_iter().map(String::from).collect()));
+        assert_eq!(Validator::group_cycle(&table, "d", &mut vec![]), None);
+        let table = groups("a = ['a']");
+        assert_eq!(Validator::group_cycle(&table, "a", &mut vec![]), Some(vec!["a".to_string(), "a".to_string()]));
+    }
+
+    #[test]
+    fn group_errors() {
+        let text = format!("{}\n[groups]\nweb = ['a', 'x']\nall = ['web', 'all']\na = ['web']\n", VALID);
+        let errors = errors(&text);
+        assert!(errors.iter().any(|x| x.ends_with("groups.web[1]: 服务器或分组 x 不存在")), "{:?}", errors);
+        assert!(errors.iter().any(|x| x.contains("groups.all") && x.contains("循环引用")), "{:?}", errors);
+        assert!(errors.iter().any(|x| x.contains("groups.a") && x.contains("重名")), "{:?}", errors);
+    }
+
+    /// 在临时目录中创建配置文件，返回目录
+    fn config_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("deploy_tool-test-{}-{}", name, std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        for (path, text) in files {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, text).unwrap();
+        }
+        dir
+    }
+
+    const SERVER_B: &str = "[server.b]\nhost = 'h'\nuser = 'u'\n";
+
+    #[test]
+    fn include_merges_in_order() {
+        let dir = config_dir("include", &[
+            ("main.toml", "include = ['servers/*.toml', 'project.toml']\n[vars]\napp = 'demo'\n"),
+            ("servers/b.toml", SERVER_B),
+            ("servers/a.toml", VALID.split("[project").next().unwrap()),
+            ("project.toml", &format!("include = ['main.toml']\n[project{}", VALID.split("[project").nth(1).unwrap())),
+        ]);
+        let mut sources = Sources::default();
+        sources.load(&dir.join("main.toml")).unwrap();
+        let names: Vec<&str> = sources.files.iter().map(|x| x.path.rsplit('/').next().unwrap()).collect();
+        assert_eq!(names, vec!["main.toml", "a.toml", "b.toml", "project.toml"]);
+        let servers: Vec<&String> = sources.value["server"].as_table().unwrap().keys().collect();
+        assert_eq!(servers, vec!["a", "b"]);
+        assert!(sources.source(&["server", "b", "host"]).path.ends_with("servers/b.toml"));
+        assert!(sources.source(&["project", "demo"]).path.ends_with("project.toml"));
+
+        let config = Config::read_config(dir.join("main.toml").to_string_lossy().to_string(), None).unwrap();
+        assert_eq!(config.servers.len(), 2);
+        assert_eq!(config.vars["app"], "demo");
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn duplicate_keys_name_both_files() {
+        let dir = config_dir("duplicate", &[
+            ("main.toml", &format!("include = ['more.toml']\n{}", SERVER_B)),
+            ("more.toml", SERVER_B),
+        ]);
+        let err = Config::read_config(dir.join("main.toml").to_string_lossy().to_string(), None).unwrap_err().to_string();
+        assert!(err.starts_with("server.b 同时在 "), "{}", err);
+        assert!(err.contains("main.toml 和 ") && err.ends_with("more.toml 中定义"), "{}", err);
+
+        std::fs::remove_dir_all(&dir).ok();
+        let dir = config_dir("duplicate-vars", &[
+            ("main.toml", "include = ['conf.d/*.toml']\n[vars]\napp = 'a'\n"),
+            ("conf.d/vars.toml", "[vars]\napp = 'b'\n"),
+        ]);
+        let err = Config::read_config(dir.join("main.toml").to_string_lossy().to_string(), None).unwrap_err().to_string();
+        assert!(err.starts_with("vars.app 同时在 "), "{}", err);
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn errors_point_to_included_file() {
+        let dir = config_dir("errors", &[
+            ("main.toml", &format!("include = ['b.toml']\n{}", VALID)),
+            ("b.toml", "[server.b]\nhost = 'h'\nuser = 'u'\nport = 'x'\n"),
+        ]);
+        let err = Config::read_config(dir.join("main.toml").to_string_lossy().to_string(), None).unwrap_err().to_string();
+        assert!(err.contains(&format!("{}:4: server.b.port: 应为整数，实际为字符串", dir.join("b.toml").display())), "{}", err);
+
+        std::fs::remove_dir_all(&dir).ok();
+        let dir = config_dir("include-type", &[("main.toml", "include = 'b.toml'\n")]);
+        let err = Config::read_config(dir.join("main.toml").to_string_lossy().to_string(), None).unwrap_err().to_string();
+        assert!(err.contains("include 应为字符串数组"), "{}", err);
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn env_placeholders_are_checked_at_deploy_time() {
+        let text = VALID.replace("target_name = 'app.jar'", "target_name = 'app.jar'\nbefore = { prod = ['echo {env.DEPLOY_TOOL_TEST_UNSET}'] }");
+        assert!(errors(&text).is_empty(), "{:?}", errors(&text));
+    }
+
+    const ALLOWED: &str = "
+[server.a]
+host = 'h'
+user = 'u'
+vars = { port = '8080' }
+
+[server.b]
+host = 'h'
+user = 'u'
+
+[server.c]
+host = 'h'
+user = 'u'
+vars = { port = '8081' }
+
+[groups]
+web = ['a', 'b']
+all = ['web', 'c']
+
+[project.demo]
+source_dir = '/tmp'
+remote_dir = '/srv'
+target_name = 'app.jar'
+servers = ['all']
+after = { prod = ['echo {port}'], test = ['echo {port}'] }
+profiles = { prod = { servers = ['a', 'c'] }, test = {} }
+";
+
+    #[test]
+    fn allowed_servers_intersects_expanded_groups() {
+        let dir = config_dir("allowed", &[("deploy.toml", &ALLOWED.replace("test = ['echo {port}']", "test = ['echo']"))]);
+        let config = Config::read_config(dir.join("deploy.toml").to_string_lossy().to_string(), None).unwrap();
+        assert_eq!(config.expand(&["all".to_string(), "a".to_string()]), vec!["a", "b", "c"]);
+        let project = &config.projects[0];
+        assert_eq!(config.allowed_servers(project, "prod"), Some(vec!["a".to_string(), "c".to_string()]));
+        assert_eq!(config.allowed_servers(project, "test"), Some(vec!["a".to_string(), "b".to_string(), "c".to_string()]));
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn after_server_vars_only_need_allowed_servers() {
+        let errors = errors(ALLOWED);
+        assert!(!errors.iter().any(|x| x.contains("after.prod")), "{:?}", errors);
+        assert!(errors.iter().any(|x| x.ends_with("project.demo.after.test[0]: 未知的占位符 {port}")), "{:?}", errors);
+    }
+
+    #[test]
+    fn rolling_numbers_must_fit_their_types() {
+        let text = VALID.replace("target_name = 'app.jar'", "target_name = 'app.jar'
+after = { prod = [{ cmd = 'true', allowed_exit_codes = [1, 4294967296] }] }
+rolling = { pause = -5, health_check = { url = 'http://localhost/', timeout = -1, interval = 0 } }");
+        let errors = errors(&text);
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+        assert!(errors[0].ends_with("project.demo.after.prod[0].allowed_exit_codes[1]: 退出码 4294967296 超出范围"), "{:?}", errors);
+        assert!(errors[1].ends_with("project.demo.rolling.pause: 间隔秒数 -5 不能小于0"), "{:?}", errors);
+        assert!(errors[2].ends_with("project.demo.rolling.health_check.timeout: 超时秒数 -1 不能小于0"), "{:?}", errors);
+    }
+
+    #[test]
+    fn server_overrides_merge_after_by_profile() {
+        let project: Value = "
+remote_dir = '/srv'
+layout = 'release'
+after = { prod = ['echo prod'], test = ['echo test'] }
+server = { a = { remote_dir = '/opt' } }
+".parse().unwrap();
+        let server: Value = "remote_dir = '/opt'
+layout = 'in_place'
+after = { test = ['echo a'], dev = ['echo dev'] }".parse().unwrap();
+        let merged = Project::merge(&project, &server);
+        assert_eq!(merged["remote_dir"].as_str(), Some("/opt"));
+        assert_eq!(merged["layout"].as_str(), Some("in_place"));
+        assert!(merged.get("server").is_none());
+        let after: Vec<(&String, &Value)> = merged["after"].as_table().unwrap().iter().collect();
+        assert_eq!(after.len(), 3);
+        assert_eq!(after[0].1.as_array().unwrap()[0].as_str(), Some("echo prod"));
+        assert_eq!(after[1].1.as_array().unwrap()[0].as_str(), Some("echo a"));
+        assert_eq!(after[2].0, "dev");
+        // 项目没有after时使用服务器的after
+        let merged = Project::merge(&"remote_dir = '/srv'".parse().unwrap(), &server);
+        assert_eq!(merged["after"].as_table().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn server_overrides_reject_artifacts_and_vars() {
+        let text = format!("{}
+[project.demo.server.a]
+artifacts = ['dist']
+vars = {{ port = '80' }}
+", VALID);
+        let errors = errors(&text);
+        assert!(errors.iter().any(|x| x.ends_with("project.demo.server.a.artifacts: 未知的配置项")), "{:?}", errors);
+        assert!(errors.iter().any(|x| x.ends_with("project.demo.server.a.vars: 未知的配置项")), "{:?}", errors);
+    }
+
+    #[test]
+    fn config_candidates_follow_search_order() {
+        let dir = config_dir("candidates", &[("env.toml", VALID), ("xdg/deploy_tool/config.toml", VALID), ("env/conf.d/a.toml", "")]);
+        env::set_var(CONFIG_ENV, dir.join("env.toml"));
+        env::set_var("XDG_CONFIG_HOME", dir.join("xdg"));
+        let candidates = ConfigPath::candidates(Some("/nonexistent/deploy.toml"));
+        let paths: Vec<&PathBuf> = candidates.iter().map(|x| &x.path).collect();
+        assert_eq!(paths[0], &PathBuf::from("/nonexistent/deploy.toml"));
+        assert_eq!(paths[1], &dir.join("env.toml"));
+        assert_eq!(paths[2], &env::current_dir().unwrap().join("deploy.toml"));
+        assert_eq!(paths[paths.len() - 3], &PathBuf::from("/deploy.toml"));
+        assert_eq!(paths[paths.len() - 2], &dir.join("xdg/deploy_tool/config.toml"));
+        assert!(paths[paths.len() - 1].ends_with(".config/deploy_tool/config.toml"));
+        let explicit: Vec<bool> = candidates.iter().map(|x| x.explicit).collect();
+        assert_eq!(&explicit[..3], &[true, true, false]);
+        assert!(candidates.iter().rev().take(2).all(|x| x.conf_d));
+
+        // 指定的文件不存在时不再继续查找
+        assert!(ConfigPath::find(Some("/nonexistent/deploy.toml")).unwrap_err().to_string().contains("--config"));
+        let config = ConfigPath::find(None).unwrap();
+        assert_eq!(config.path, dir.join("env.toml"));
+        assert_eq!(config.conf_dir(), None);
+
+        env::remove_var(CONFIG_ENV);
+        let config = ConfigPath { path: dir.join("env/config.toml"), reason: String::new(), conf_d: false };
+        assert_eq!(config.skipped_conf_dir(), Some(dir.join("env/conf.d")));
+        let config = ConfigPath { conf_d: true, ..config };
+        assert_eq!(config.conf_dir(), Some(dir.join("env/conf.d")));
+        assert_eq!(config.skipped_conf_dir(), None);
+        env::remove_var("XDG_CONFIG_HOME");
+        std::fs::remove_dir_all(dir).ok();
     }
 }
@@ -1,40 +1,122 @@
-use std::collections::HashMap;
-use std::path::Path;
-use std::process::exit;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Result};
+use chrono::Local;
 use dialoguer::{MultiSelect, Select};
-use dialoguer::console::{style, Term};
+use dialoguer::console::{measure_text_width, pad_str, style, Alignment, Term};
+use indexmap::IndexMap;
+use indicatif::{HumanBytes, MultiProgress, ProgressBar};
 
-use crate::config::{Config, Project, Server};
+use crate::artifact::UploadFile;
+use crate::backup::Backup;
+use crate::cache::BuildCache;
+use crate::config::{Cmd, Config, Dirty, HealthCheck, Layout, Project, Server, UploadMethod};
+use crate::git::{GitInfo, Worktree};
+use crate::history::{elapsed_ms, History, HistoryEntry, HistoryFilter, ServerRecord, Status};
+use crate::manifest::Manifest;
+use crate::release::Release;
+use crate::sync::SyncPlan;
 use crate::utils;
-use crate::utils::SshUtil;
+use crate::utils::{quote, Logger, SshUtil};
+use crate::vars::Vars;
+
+/// 命令行指定的部署目标，未指定的部分通过交互选择
+#[derive(Debug, Clone, Default)]
+pub struct DeployArgs {
+    pub project: Option<String>,
+    pub servers: Vec<String>,
+    /// 服务器分组，展开为分组中的全部服务器
+    pub groups: Vec<String>,
+    /// `key=value` 或 `key` 形式的标签，服务器需要匹配全部标签
+    pub tags: Vec<String>,
+    pub all_servers: bool,
+    pub profile: Option<String>,
+    /// 同时部署的服务器数量，未指定时使用项目的parallelism配置
+    pub parallel: Option<usize>,
+    /// 部署完成后保留本地的部署文件
+    pub keep_artifact: bool,
+    /// 跳过部署前置操作，复用缓存的构建，latest为最新的构建
+    pub reuse_build: Option<String>,
+    /// 检出到临时工作区后构建的git分支、标签或提交
+    pub git_ref: Option<String>,
+    /// 只输出部署计划，不执行任何操作
+    pub dry_run: bool,
+    /// 输出部署计划时登录服务器检查认证和目录
+    pub verify: bool,
+}
+
+/// 时间戳格式，用于备份文件名称
+const TIMESTAMP_FORMAT: &str = "%Y%m%d-%H%M%S";
+
+/// 上传中断后重试前等待的秒数
+const RETRY_DELAY: u64 = 3;
+
+/// 一次部署中所有服务器共用的信息
+#[derive(Debug, Clone)]
+pub struct DeployTask {
+    pub project: Project,
+    pub after: Vec<Cmd>,
+    /// 需要上传的文件
+    pub files: Vec<UploadFile>,
+    /// 本次部署的时间戳
+    pub timestamp: String,
+    /// 部署完成后写入服务器的部署记录
+    pub manifest: Manifest,
+    /// after命令中占位符的取值，不包含服务器变量
+    pub vars: Vars,
+}
+
+impl DeployTask {
+    /// 服务器使用的部署信息，使用按服务器覆盖后的项目配置，after命令中的占位符替换为服务器的取值
+    fn for_server(&self, server: &Server) -> Result<DeployTask> {
+        let project = self.project.for_server(&server.name).clone();
+        let after = project.after.get(&self.manifest.profile).cloned().unwrap_or_else(|| self.after.clone());
+        let after = self.vars.with_server(server, &project).render_cmds(&after)?;
+        // 未配置artifacts时按服务器覆盖的target_name作为服务器上的文件名
+        let mut files = self.files.clone();
+        if project.artifacts.is_empty() && project.target_name != self.project.target_name {
+            for file in files.iter_mut().filter(|x| x.remote == Path::new(&self.project.target_name)) {
+                file.remote = PathBuf::from(&project.target_name);
+            }
+        }
+        let manifest = Manifest { after: after.iter().map(|x| x.cmd.clone()).collect(), ..self.manifest.clone() };
+        Ok(DeployTask { project, after, files, manifest, ..self.clone() })
+    }
+}
 
 pub struct DeployUtil {
     pub cmd: utils::CmdUtil,
     pub config: Config,
     pub term: Term,
     pub key: Option<String>,
+    pub args: DeployArgs,
+    /// 指定git_ref时的临时工作区，部署结束后删除
+    pub worktree: Option<Worktree>,
 }
 
 impl DeployUtil {
-    pub fn new(config_path: String) -> DeployUtil {
+    pub fn new(config_path: String, conf_dir: Option<PathBuf>, args: DeployArgs) -> Result<DeployUtil> {
         let cmd = utils::CmdUtil::new();
-        let config = Config::read_config(config_path).unwrap();
+        let config = Config::read_config(config_path, conf_dir)?;
         let term = Term::stdout();
-        DeployUtil { cmd, config, term, key: None }
+        let key = args.profile.clone();
+        Ok(DeployUtil { cmd, config, term, key, args, worktree: None })
     }
 
-    fn login_server(&mut self, host: &String, port: &i64, user: &String, password: &String, key_str: &String, identity_file: &String) -> Result<SshUtil> {
-        match SshUtil::new(host.clone(), port.clone()) {
+    fn login_server(server: &Server) -> Result<SshUtil> {
+        match SshUtil::new(server.host.clone(), server.port) {
             Ok(mut ssh) => {
-                if !identity_file.is_empty() {
-                    ssh.login_width_pem(user.clone(), identity_file.clone())?;
-                } else if !key_str.is_empty() {
-                    let private_key = Path::new(key_str);
-                    ssh.login_with_pubkey(user.clone(), private_key)?;
+                if !server.identity_file.is_empty() {
+                    ssh.login_width_pem(server.user.clone(), server.identity_file.clone())?;
+                } else if !server.private_key.is_empty() {
+                    let private_key = Path::new(&server.private_key);
+                    ssh.login_with_pubkey(server.user.clone(), private_key)?;
                 } else {
-                    ssh.login_with_pwd(user.clone(), password.clone())?;
+                    ssh.login_with_pwd(server.user.clone(), server.password.clone())?;
                 }
                 Ok(ssh)
             }
@@ -42,113 +124,925 @@ impl DeployUtil {
         }
     }
 
-    fn deploy(&mut self, project: &Project, server: &Server) -> Result<()> {
-        self.term.write_line(&format!("{} 部署开始！", server.name))?;
-        match self.login_server(&server.host, &server.port, &server.user, &server.password, &server.private_key, &server.identity_file) {
+    fn deploy(task: &DeployTask, server: &Server, logger: Logger, record: &mut ServerRecord) -> Result<()> {
+        let task = &task.for_server(server)?;
+        let project = &task.project;
+        logger.line(&format!("{} 部署开始！", server.name));
+        if let Some(git) = &task.manifest.git {
+            logger.line(format!("部署版本：{} {}", git.describe(), git.branch).trim_end());
+        }
+        logger.status("登录服务器");
+        match DeployUtil::login_server(server) {
             Err(err) => Err(anyhow!(err.to_string())),
             Ok(mut ssh) => {
-                let file_path = Path::new(&project.source_dir).join(&project.target_name);
-                let target_path = Path::new(&project.remote_dir);
-                ssh.check_dir(target_path)?;
-                ssh.upload_file(file_path.as_path(), target_path.join(&project.target_name).as_path())?;
-                std::fs::remove_file(file_path)?;
+                ssh.logger = logger.clone();
+                match project.layout {
+                    Layout::InPlace => DeployUtil::deploy_in_place(task, server, &mut ssh, record)?,
+                    Layout::Release => DeployUtil::deploy_release(task, server, &mut ssh, record)?,
+                }
+                logger.line(&format!("{} 部署完成！", server.name));
+                Ok(())
+            }
+        }
+    }
+
+    /// 备份并覆盖remote_dir中的部署文件
+    fn deploy_in_place(task: &DeployTask, server: &Server, ssh: &mut SshUtil, record: &mut ServerRecord) -> Result<()> {
+        let project = &task.project;
+        let target_path = Path::new(&project.remote_dir);
+        ssh.check_dir(target_path)?;
+        let backup = Backup::create(ssh, project, &task.timestamp)?;
+        if let Some(backup) = &backup {
+            ssh.logger.line(&format!("已备份当前部署文件：{}", backup.name));
+        }
+        let start = Instant::now();
+        let result = match &project.sync {
+            Some(options) => {
+                let plan = SyncPlan::new(ssh, &task.files, target_path, options)?;
+                plan.print(&ssh.logger);
+                DeployUtil::upload(task, server, ssh, target_path, &plan.upload).and_then(|_| plan.delete(ssh, target_path))
+            }
+            None => DeployUtil::upload(task, server, ssh, target_path, &task.files)
+        };
+        record.upload_ms = elapsed_ms(start);
+        if let Err(err) = result {
+            if let Some(backup) = &backup {
+                backup.restore(ssh, project)?;
+                ssh.logger.line(&format!("上传失败，已恢复备份：{}", backup.name));
+            }
+            return Err(err);
+        }
+
+        let start = Instant::now();
+        let result = DeployUtil::after_deploy(project, ssh, &task.after, None);
+        record.after_ms = elapsed_ms(start);
+        result?;
+        task.manifest.write(ssh, project, target_path)?;
+        Backup::prune(ssh, project)
+    }
+
+    /// 上传到新的版本目录，after命令执行成功后切换current链接
+    fn deploy_release(task: &DeployTask, server: &Server, ssh: &mut SshUtil, record: &mut ServerRecord) -> Result<()> {
+        let project = &task.project;
+        let release = Release::new(&task.timestamp);
+        let release_dir = release.dir(project);
+        ssh.check_dir(&release_dir)?;
+        let start = Instant::now();
+        let result = DeployUtil::upload(task, server, ssh, &release_dir, &task.files);
+        record.upload_ms = elapsed_ms(start);
+
+        let result = result.and_then(|_| {
+            let start = Instant::now();
+            let result = DeployUtil::after_deploy(project, ssh, &task.after, Some(&release_dir));
+            record.after_ms = elapsed_ms(start);
+            result
+        });
+        let result = result
+            .and_then(|_| task.manifest.write(ssh, project, &release_dir))
+            .and_then(|_| release.activate(ssh, project));
+        if let Err(err) = result {
+            // 未切换current的版本不能用于回滚，连接已断开时由下次部署的prune删除
+            match release.remove(ssh, project) {
+                Ok(()) => ssh.logger.line(&format!("部署失败，已删除版本目录：{}", release.name)),
+                Err(remove_err) => ssh.logger.line(&style(format!("删除版本目录 {} 失败！({})", release.name, remove_err)).yellow().to_string()),
+            }
+            return Err(err);
+        }
+        ssh.logger.line(&format!("current已切换到版本：{}", release.name));
+        Release::prune(ssh, project)
+    }
+
+    /// 上传部署文件到target_dir，按目录结构创建远程目录
+    fn upload(task: &DeployTask, server: &Server, ssh: &mut SshUtil, target_dir: &Path, files: &[UploadFile]) -> Result<()> {
+        for file in files {
+            let remote = target_dir.join(&file.remote);
+            if let Some(parent) = remote.parent() {
+                ssh.check_dir(parent)?;
+            }
+            DeployUtil::upload_file(&task.project, server, ssh, file, &remote)?;
+            let remote_hash = ssh.sha256(&remote)?;
+            if remote_hash != file.sha256 {
+                ssh.remove_file(&remote)?;
+                return Err(anyhow!("文件 {} 校验失败，本地SHA-256为 {}，服务器上为 {}，已删除上传的文件",
+                                   remote.display(), file.sha256, remote_hash));
+            }
+        }
+        Ok(())
+    }
 
-                let after = project.after.clone();
+    /// 全部服务器部署完成后删除本地的target_name，部署失败或配置了keep_artifact时保留，artifacts中的文件不删除
+    fn cleanup(&self, project: &Project, success: bool) -> Result<()> {
+        if !project.artifacts.is_empty() {
+            return Ok(());
+        }
+        let file_path = Path::new(&project.source_dir).join(&project.target_name);
+        if self.args.keep_artifact || project.keep_artifact {
+            self.term.write_line(&format!("已保留部署文件：{}", file_path.display()))?;
+        } else if !success {
+            self.term.write_line(&format!("部分服务器部署失败，已保留部署文件：{}", file_path.display()))?;
+        } else if file_path.is_file() {
+            std::fs::remove_file(file_path)?;
+        }
+        Ok(())
+    }
 
-                for cmd in self.get_cmds(after) {
-                    ssh.exec(cmd)?;
+    /// 上传单个文件，连接中断时重新登录并重试，sftp方式从临时文件的断点继续上传
+    fn upload_file(project: &Project, server: &Server, ssh: &mut SshUtil, file: &UploadFile, remote: &Path) -> Result<()> {
+        let mut retries = 0;
+        loop {
+            let result = match project.upload_method {
+                UploadMethod::Scp => ssh.upload_file(&file.local, remote, file.mode),
+                UploadMethod::Sftp => {
+                    let name = remote.file_name().unwrap_or_default().to_string_lossy();
+                    let part = remote.with_file_name(format!("{}.{}.part", name, &file.sha256[..8]));
+                    ssh.upload_file_resumable(&file.local, remote, file.mode, &part)
                 }
-                self.term.write_line(&format!("{} 部署完成！", server.name))?;
-                Ok(())
+            };
+            let mut err = match result {
+                Ok(()) => return Ok(()),
+                Err(err) => err
+            };
+            // 重新连接失败也计为一次重试，网络恢复前可能连续失败
+            loop {
+                if retries >= project.upload_retries {
+                    return Err(err);
+                }
+                retries += 1;
+                ssh.logger.line(&style(format!("上传中断，{}秒后第 {} 次重试！({})", RETRY_DELAY, retries, err)).yellow().to_string());
+                thread::sleep(Duration::from_secs(RETRY_DELAY));
+                match DeployUtil::login_server(server) {
+                    Ok(session) => {
+                        let logger = ssh.logger.clone();
+                        *ssh = session;
+                        ssh.logger = logger;
+                        break;
+                    }
+                    Err(login_err) => err = anyhow!("重新连接失败：{}", login_err)
+                }
+            }
+        }
+    }
+
+    /// 执行after命令，work_dir不为空时在该目录下执行
+    fn after_deploy(project: &Project, ssh: &mut SshUtil, after: &[Cmd], work_dir: Option<&Path>) -> Result<()> {
+        for cmd in after {
+            let line = match work_dir {
+                Some(dir) => format!("cd {} && {}", quote(&dir.to_string_lossy()), cmd.cmd),
+                None => cmd.cmd.clone()
+            };
+            if let Err(err) = ssh.exec(line, &cmd.allowed_exit_codes) {
+                if !DeployUtil::ignore_error(project, cmd) {
+                    return Err(err);
+                }
+                ssh.logger.line(&style(format!("{}\n已配置continue_on_error，继续执行！", err)).yellow().to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// 重复执行健康检查直到成功，超时后返回最后一次的错误
+    fn health_check(server: &Server, health_check: &HealthCheck) -> Result<()> {
+        let logger = Logger::new(server.name.clone(), ProgressBar::hidden());
+        logger.line("开始健康检查");
+        let mut ssh = DeployUtil::login_server(server)?;
+        ssh.logger = logger.clone();
+        let cmd = health_check.command();
+        let start = Instant::now();
+        loop {
+            match ssh.exec(cmd.clone(), &[]) {
+                Ok(()) => {
+                    logger.line("健康检查通过");
+                    return Ok(());
+                }
+                Err(err) if start.elapsed() >= Duration::from_secs(health_check.timeout) => {
+                    return Err(anyhow!("健康检查超时({}秒)：{}", health_check.timeout, err));
+                }
+                Err(_) => thread::sleep(Duration::from_secs(health_check.interval.max(1)))
             }
         }
     }
 
-    fn before_deploy(&mut self, project: &Project) -> Result<()> {
+    /// 同时部署多台服务器，每台服务器一个进度条，parallel为同时部署的数量
+    fn deploy_servers(task: &DeployTask, servers: Vec<Server>, parallel: usize) -> Vec<ServerRecord> {
+        let multi = MultiProgress::new();
+        let prefixed = servers.len() > 1;
+        let queue: Vec<(usize, Server, ProgressBar)> = servers.into_iter().enumerate().map(|(index, server)| {
+            let pb = multi.add(ProgressBar::new_spinner());
+            pb.set_prefix(&server.name);
+            pb.set_message("等待部署");
+            (index, server, pb)
+        }).collect();
+        let queue = Mutex::new(queue.into_iter());
+        let results = Mutex::new(vec![]);
+        thread::scope(|scope| {
+            for _ in 0..parallel {
+                scope.spawn(|| loop {
+                    let next = queue.lock().unwrap().next();
+                    let (index, server, pb) = match next {
+                        Some(item) => item,
+                        None => break
+                    };
+                    let prefix = if prefixed { server.name.clone() } else { String::new() };
+                    let mut record = ServerRecord::new(&server.name);
+                    let start = Instant::now();
+                    let result = DeployUtil::deploy(task, &server, Logger::new(prefix, pb.clone()), &mut record);
+                    record.total_ms = elapsed_ms(start);
+                    match result {
+                        Ok(()) => {
+                            record.status = Status::Success;
+                            pb.finish_with_message("部署完成");
+                        }
+                        Err(err) => {
+                            record.status = Status::Failed;
+                            record.error = Some(err.to_string());
+                            pb.abandon_with_message("部署失败");
+                        }
+                    }
+                    results.lock().unwrap().push((index, record));
+                });
+            }
+            multi.join().ok();
+        });
+        let mut results = results.into_inner().unwrap();
+        results.sort_by_key(|(index, _)| *index);
+        results.into_iter().map(|(_, record)| record).collect()
+    }
+
+    fn before_deploy(&mut self, project: &Project, timestamp: &str, git: Option<&GitInfo>) -> Result<()> {
         self.term.write_line("开始部署前置操作")?;
         let source_dir = project.source_dir.clone();
         let target_file = Path::new(&source_dir).join(&project.target_name);
-        if target_file.exists() {
+        if project.artifacts.is_empty() && target_file.is_file() {
             std::fs::remove_file(target_file)?;
         }
 
         self.cmd.change_path(source_dir);
 
-        let before = project.before.clone();
-        for cmd in self.get_cmds(before) {
-            self.cmd.exec(cmd)?;
+        let before = self.get_cmds(project.before.clone())?;
+        for cmd in self.vars(project, timestamp, git).render_cmds(&before)? {
+            if let Err(err) = self.cmd.exec(cmd.cmd.clone(), &cmd.allowed_exit_codes) {
+                if !DeployUtil::ignore_error(project, &cmd) {
+                    return Err(err);
+                }
+                self.term.write_line(&style(format!("{}\n已配置continue_on_error，继续执行！", err)).yellow().to_string())?;
+            }
         }
         self.term.write_line("完成部署前置操作!")?;
         Ok(())
     }
 
-    fn get_cmds(&self, cmd_map: HashMap<String, Vec<String>>) -> Vec<String> {
+    /// 命令或项目配置了continue_on_error时只提示错误，否则中止部署
+    fn ignore_error(project: &Project, cmd: &Cmd) -> bool {
+        cmd.continue_on_error || project.continue_on_error
+    }
+
+    fn get_cmds(&mut self, cmd_map: IndexMap<String, Vec<Cmd>>) -> Result<Vec<Cmd>> {
         let keys: Vec<String> = cmd_map.keys().map(|x| x.to_string()).collect();
-        if keys.len() as i32 > 1 {
-            match &self.key {
-                Some(k) => {
-                    cmd_map.get(k).unwrap().clone()
-                }
-                None => {
-                    let key_index = DeployUtil::choose_profile(keys.clone());
-                    let key = keys.get(key_index).unwrap().to_string();
-                    cmd_map.get(&key).unwrap().clone()
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+        let key = match &self.key {
+            Some(k) => k.clone(),
+            None if keys.len() > 1 => {
+                let key_index = DeployUtil::choose_profile(keys.clone());
+                let key = keys.get(key_index).unwrap().to_string();
+                self.key = Some(key.clone());
+                key
+            }
+            None => {
+                let key = keys.first().unwrap().to_string();
+                self.key = Some(key.clone());
+                key
+            }
+        };
+        match cmd_map.get(&key) {
+            Some(cmds) => Ok(cmds.clone()),
+            None => Err(anyhow!("配置 {} 不存在，可选配置：{}", key, keys.join(", ")))
+        }
+    }
+
+    /// 当前使用的before和after配置项，没有配置时为default，用于区分缓存的构建
+    fn profile(&self) -> String {
+        self.key.clone().unwrap_or_else(|| "default".to_string())
+    }
+
+    /// 执行部署前置操作并缓存构建结果，指定了reuse_build时跳过前置操作，使用缓存的部署文件
+    fn build(&mut self, project: &Project, timestamp: &str) -> Result<(Vec<UploadFile>, Option<GitInfo>)> {
+        if let Some(id) = self.args.reuse_build.clone() {
+            // 只用于选择配置项，不执行before命令
+            self.get_cmds(project.before.clone())?;
+            let build = BuildCache::find(&project.name, &self.profile(), &id)?;
+            self.term.write_line(&format!("跳过部署前置操作，使用缓存的构建：{} ({})", build.id, build.created))?;
+            let source_dir = build.source_dir().to_string_lossy().to_string();
+            return Ok((UploadFile::resolve(&Project { source_dir, ..project.clone() })?, build.git));
+        }
+        let project = match &self.args.git_ref {
+            Some(git_ref) => {
+                let worktree = Worktree::create(&project.source_dir, git_ref, &project.name)?;
+                self.term.write_line(&format!("已检出 {} 到临时目录：{}", git_ref, worktree.path.display()))?;
+                let source_dir = worktree.dir();
+                self.worktree = Some(worktree);
+                Project { source_dir, ..project.clone() }
+            }
+            None => project.clone()
+        };
+        let git = GitInfo::read(&project.source_dir);
+        if let Some(git) = &git {
+            let branch = if git.branch.is_empty() { "分离头指针".to_string() } else { git.branch.clone() };
+            self.term.write_line(&format!("git提交：{} ({})", git.commit, branch))?;
+            if git.dirty {
+                match project.git.dirty {
+                    Dirty::Refuse => return Err(anyhow!("{} 有未提交的修改，已中止部署！", project.source_dir)),
+                    Dirty::Warn => self.term.write_line(&style(format!("{} 有未提交的修改！", project.source_dir)).yellow().to_string())?,
+                    Dirty::Allow => {}
                 }
             }
-        } else {
-            let key = keys.get(0).unwrap().to_string();
-            cmd_map.get(&key).unwrap().clone()
         }
+        if let Err(err) = self.before_deploy(&project, timestamp, git.as_ref()) {
+            return Err(anyhow!("部署前置操作失败，已中止部署！({})", err));
+        }
+        let files = UploadFile::resolve(&project)?;
+        match BuildCache::store(&project, &self.profile(), &files, git.as_ref()) {
+            Ok(build) => self.term.write_line(&format!("已缓存构建：{}", build.id))?,
+            Err(err) => self.term.write_line(&style(format!("缓存构建失败！({})", err)).yellow().to_string())?
+        }
+        Ok((files, git))
+    }
+
+    /// 命令中占位符的取值，profile为当前选择的配置项
+    fn vars(&self, project: &Project, timestamp: &str, git: Option<&GitInfo>) -> Vars {
+        let git_commit = git.map(|x| x.commit.as_str()).unwrap_or_default();
+        Vars::new(&self.config.vars, project, &self.profile(), timestamp, git_commit)
     }
 
     fn choose_profile(keys: Vec<String>) -> usize {
         Select::new().items(&keys).default(0).with_prompt("请选择").interact().unwrap()
     }
 
-    fn choose_project_and_server(projects: &Vec<Project>, servers: &Vec<Server>) -> (usize, Vec<usize>) {
+    fn choose_project(projects: &[Project]) -> usize {
         let mut items: Vec<String> = Vec::new();
-        for i in 0..projects.len() {
-            items.push(format!("{}\n", projects.get(i).unwrap().name));
+        for project in projects {
+            items.push(format!("{}\n", project.name));
         }
-        let select_project = Select::new().items(&items).default(0)
-            .with_prompt("请选择需要部署的项目(默认选择第一个)").interact().unwrap();
+        Select::new().items(&items).default(0)
+            .with_prompt("请选择需要部署的项目(默认选择第一个)").interact().unwrap()
+    }
 
-        let mut select_server: Vec<usize> = Vec::new();
-        items.clear();
-        for i in 0..servers.len() {
-            items.push(format!("{}", servers.get(i).unwrap().name));
-        }
+    /// 分组排在服务器之前，选择分组时部署到分组中的全部服务器，allowed不为None时只列出允许部署的服务器和分组
+    fn choose_server(&self, servers: &[Server], allowed: Option<&[String]>) -> Vec<usize> {
+        let is_allowed = |name: &String| allowed.map(|x| x.contains(name)).unwrap_or(true);
+        let groups: Vec<(&String, Vec<String>)> = self.config.groups.keys()
+            .map(|x| (x, self.config.group_servers(x).into_iter().filter(is_allowed).collect::<Vec<String>>()))
+            .filter(|(_, members)| !members.is_empty())
+            .collect();
+        let candidates: Vec<usize> = (0..servers.len()).filter(|x| is_allowed(&servers[*x].name)).collect();
+        let mut items: Vec<String> = groups.iter()
+            .map(|(name, members)| format!("[分组] {} ({})", name, members.join(", ")))
+            .collect();
+        items.extend(candidates.iter().map(|x| servers[*x].name.clone()));
         let mut select: Vec<usize> = MultiSelect::new().items(&items).with_prompt("请选择目标服务器").interact().unwrap();
         while select.is_empty() {
             select = MultiSelect::new().items(&items).with_prompt("请选择目标服务器").interact().unwrap();
         }
-        for i in select {
-            select_server.push(i);
+        let mut server_index = vec![];
+        for index in select {
+            let selected: Vec<usize> = match groups.get(index) {
+                Some((_, members)) => members.iter()
+                    .filter_map(|name| servers.iter().position(|x| &x.name == name))
+                    .collect(),
+                None => vec![candidates[index - groups.len()]]
+            };
+            for index in selected {
+                if !server_index.contains(&index) {
+                    server_index.push(index);
+                }
+            }
+        }
+        server_index
+    }
+
+    /// 命令行指定的服务器和分组展开后的服务器名称
+    fn named_servers(&self) -> Result<Vec<String>> {
+        let mut names = self.args.servers.clone();
+        for group in &self.args.groups {
+            if !self.config.groups.contains_key(group) {
+                let groups: Vec<String> = self.config.groups.keys().cloned().collect();
+                return Err(anyhow!("分组 {} 不存在，可选分组：{}", group, groups.join(", ")));
+            }
+            names.extend(self.config.group_servers(group));
+        }
+        Ok(names)
+    }
+
+    /// 命令行指定的服务器和分组，再按标签过滤，只指定了标签时从全部服务器中过滤，都未指定时返回None
+    ///
+    /// 指定了project时，--all-servers和只指定标签只从允许部署的服务器中选择，明确指定了不允许部署的服务器时返回错误
+    fn selected_servers(&self, servers: &[Server], project: Option<&Project>) -> Result<Option<Vec<usize>>> {
+        let allowed = project.and_then(|x| self.config.allowed_servers(x, &self.profile()));
+        let allowed = allowed.as_deref();
+        let is_allowed = |index: &usize| allowed.map(|x| x.contains(&servers[*index].name)).unwrap_or(true);
+        if self.args.all_servers {
+            return Ok(Some((0..servers.len()).filter(is_allowed).collect()));
+        }
+        let names = self.named_servers()?;
+        let mut server_index: Vec<usize> = match (names.is_empty(), self.args.tags.is_empty()) {
+            (true, true) => return Ok(None),
+            (true, false) => (0..servers.len()).filter(is_allowed).collect(),
+            (false, _) => DeployUtil::find_servers(servers, &names)?
+        };
+        let denied: Vec<String> = server_index.iter().filter(|x| !is_allowed(x)).map(|x| servers[*x].name.clone()).collect();
+        if !denied.is_empty() {
+            return Err(anyhow!("项目 {} 的配置 {} 不能部署到服务器 {}，可部署的服务器：{}",
+                               project.map(|x| x.name.as_str()).unwrap_or_default(), self.profile(), denied.join(", "),
+                               allowed.unwrap_or_default().join(", ")));
+        }
+        server_index.retain(|index| self.args.tags.iter().all(|tag| servers[*index].has_tag(tag)));
+        if server_index.is_empty() {
+            return Err(anyhow!("没有符合标签 {} 的服务器！", self.args.tags.join(", ")));
+        }
+        Ok(Some(server_index))
+    }
+
+    fn find_project(projects: &[Project], name: &str) -> Result<usize> {
+        match projects.iter().position(|x| x.name == name) {
+            Some(index) => Ok(index),
+            None => {
+                let names: Vec<String> = projects.iter().map(|x| x.name.clone()).collect();
+                Err(anyhow!("项目 {} 不存在，可选项目：{}", name, names.join(", ")))
+            }
+        }
+    }
+
+    fn find_servers(servers: &[Server], names: &[String]) -> Result<Vec<usize>> {
+        let mut select = vec![];
+        let mut missing = vec![];
+        for name in names {
+            match servers.iter().position(|x| &x.name == name) {
+                Some(index) => if !select.contains(&index) { select.push(index) },
+                None => missing.push(name.clone())
+            }
+        }
+        if missing.is_empty() {
+            Ok(select)
+        } else {
+            let names: Vec<String> = servers.iter().map(|x| x.name.clone()).collect();
+            Err(anyhow!("服务器 {} 不存在，可选服务器：{}", missing.join(", "), names.join(", ")))
+        }
+    }
+
+    fn check_profile(project: &Project, profile: &str) -> Result<()> {
+        for cmd_map in [&project.before, &project.after].iter() {
+            if !cmd_map.is_empty() && !cmd_map.contains_key(profile) {
+                let keys: Vec<String> = cmd_map.keys().map(|x| x.to_string()).collect();
+                return Err(anyhow!("项目 {} 中配置 {} 不存在，可选配置：{}", project.name, profile, keys.join(", ")));
+            }
+        }
+        Ok(())
+    }
+
+    /// 先选择项目和配置项，再从项目和配置项允许部署的服务器中选择目标服务器
+    fn select_target(&mut self, projects: &[Project], servers: &[Server]) -> Result<(usize, Vec<usize>)> {
+        let project_index = match &self.args.project {
+            Some(name) => DeployUtil::find_project(projects, name)?,
+            None => DeployUtil::choose_project(projects)
+        };
+        let project = projects.get(project_index).unwrap();
+        if let Some(profile) = &self.args.profile {
+            DeployUtil::check_profile(project, profile)?;
+        }
+        // 只用于选择配置项，before为空时从after中选择
+        self.get_cmds(if project.before.is_empty() { project.after.clone() } else { project.before.clone() })?;
+        let allowed = self.config.allowed_servers(project, &self.profile());
+        if allowed.as_ref().map(|x| x.is_empty()).unwrap_or(false) {
+            return Err(anyhow!("项目 {} 的配置 {} 没有可部署的服务器！", project.name, self.profile()));
         }
-        (select_project, select_server)
+        let server_index = match self.selected_servers(servers, Some(project))? {
+            Some(server_index) => server_index,
+            None => self.choose_server(servers, allowed.as_deref())
+        };
+        if server_index.is_empty() {
+            return Err(anyhow!("没有可部署的服务器！"));
+        }
+        Ok((project_index, server_index))
     }
 
     pub fn run(&mut self) -> Result<()> {
         let projects = self.config.projects.to_vec();
         let servers = self.config.servers.to_vec();
-        let (project_index, server_index) = DeployUtil::choose_project_and_server(&projects, &servers);
+        let (project_index, server_index) = self.select_target(&projects, &servers)?;
         let project = projects.get(project_index).unwrap();
+        let targets: Vec<Server> = server_index.iter().map(|index| servers.get(*index).unwrap().clone()).collect();
+
+        if self.args.dry_run {
+            return self.dry_run(project, &targets);
+        }
+        let names: Vec<String> = targets.iter().map(|x| x.name.clone()).collect();
+        let mut entry = HistoryEntry::new(&project.name, &names);
+        let start = Instant::now();
+        let result = self.deploy_project(project, targets, &mut entry);
+        entry.total_ms = elapsed_ms(start);
+        entry.profile = self.profile();
+        entry.status = if result.is_ok() { Status::Success } else { Status::Failed };
+        entry.error = result.as_ref().err().map(|x| x.to_string());
+        if let Err(err) = History::append(&entry) {
+            self.term.write_line(&style(format!("写入部署日志失败！({})", err)).yellow().to_string())?;
+        }
+        result
+    }
+
+    /// 构建并部署到targets，每台服务器的结果和各阶段耗时记录到entry
+    fn deploy_project(&mut self, project: &Project, targets: Vec<Server>, entry: &mut HistoryEntry) -> Result<()> {
+        let timestamp = Local::now().format(TIMESTAMP_FORMAT).to_string();
+        let start = Instant::now();
+        let result = self.build(project, &timestamp);
+        entry.before_ms = elapsed_ms(start);
+        let (files, git) = result?;
+        let after = self.get_cmds(project.after.clone())?;
+        let vars = self.vars(project, &timestamp, git.as_ref());
+        let build = BuildCache::build_id(&files, git.as_ref());
+        entry.build = build.clone();
+        let manifest = Manifest::new(project, &self.profile(), &build, &files,
+                                     after.iter().map(|x| x.cmd.clone()).collect(), &timestamp, git);
+        let task = DeployTask { project: project.clone(), after, files, timestamp, manifest, vars };
+        let size: u64 = task.files.iter().map(|x| x.size).sum();
+        self.term.write_line(&format!("待上传文件 {} 个，共 {}", task.files.len(), HumanBytes(size)))?;
+        let batch_len = match &project.rolling {
+            Some(rolling) => rolling.batch_len(targets.len()),
+            None => targets.len()
+        };
+        let batches: Vec<Vec<Server>> = targets.chunks(batch_len).map(|x| x.to_vec()).collect();
+
+        let mut failed = vec![];
+        for (index, batch) in batches.iter().enumerate() {
+            if batches.len() > 1 {
+                let names: Vec<String> = batch.iter().map(|x| x.name.clone()).collect();
+                self.term.write_line(&format!("开始部署第 {}/{} 批：{}", index + 1, batches.len(), names.join(", ")))?;
+            }
+            let parallel = self.args.parallel.or(project.parallelism)
+                .unwrap_or(if project.rolling.is_some() { batch.len() } else { 1 }).max(1).min(batch.len());
+            for record in DeployUtil::deploy_servers(&task, batch.clone(), parallel) {
+                if let Some(err) = &record.error {
+                    self.term.write_line(&style(format!("服务器 {} 部署失败！({})", &record.server, err)).red().cyan().to_string())?;
+                    failed.push(record.server.clone());
+                }
+                if let Some(item) = entry.servers.iter_mut().find(|x| x.server == record.server) {
+                    *item = record;
+                }
+            }
+            if failed.is_empty() {
+                if let Some(health_check) = project.rolling.as_ref().and_then(|x| x.health_check.as_ref()) {
+                    for server in batch {
+                        if let Err(err) = DeployUtil::health_check(server, health_check) {
+                            self.term.write_line(&style(format!("服务器 {} 健康检查失败！({})", &server.name, err)).red().cyan().to_string())?;
+                            failed.push(server.name.clone());
+                            if let Some(item) = entry.servers.iter_mut().find(|x| x.server == server.name) {
+                                item.status = Status::Failed;
+                                item.error = Some(format!("健康检查失败：{}", err));
+                            }
+                        }
+                    }
+                }
+            }
+            let remaining: Vec<String> = batches[index + 1..].iter().flatten().map(|x| x.name.clone()).collect();
+            if remaining.is_empty() {
+                break;
+            }
+            if !failed.is_empty() {
+                self.term.write_line(&style(format!("滚动部署已中止，以下服务器未部署：{}", remaining.join(", "))).yellow().to_string())?;
+                break;
+            }
+            let pause = project.rolling.as_ref().map(|x| x.pause).unwrap_or(0);
+            if pause > 0 {
+                self.term.write_line(&format!("等待 {} 秒后部署下一批", pause))?;
+                thread::sleep(Duration::from_secs(pause));
+            }
+        }
+        if self.args.reuse_build.is_none() && self.worktree.is_none() {
+            self.cleanup(project, failed.is_empty())?;
+        }
+        self.worktree = None;
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!("服务器 {} 部署失败！", failed.join(", ")))
+        }
+    }
+
+    /// 输出部署计划，不执行本地命令，也不修改服务器，verify为true时登录服务器检查认证和目录
+    fn dry_run(&mut self, project: &Project, targets: &[Server]) -> Result<()> {
+        let term = self.term.clone();
+        term.write_line(&style("部署计划(--dry-run，不会执行任何操作)").bold().to_string())?;
+        let before = self.get_cmds(project.before.clone())?;
+        let after = self.get_cmds(project.after.clone())?;
+        term.write_line(&format!("项目：{}，配置：{}", project.name, self.profile()))?;
+        let timestamp = Local::now().format(TIMESTAMP_FORMAT).to_string();
+        let mut git = GitInfo::read(&project.source_dir);
+
+        let mut files = vec![];
+        let mut pending = vec![];
+        match &self.args.reuse_build {
+            Some(id) => {
+                let build = BuildCache::find(&project.name, &self.profile(), id)?;
+                term.write_line(&format!("跳过部署前置操作，使用缓存的构建：{} ({})", build.id, build.created))?;
+                let source_dir = build.source_dir().to_string_lossy().to_string();
+                files = UploadFile::resolve(&Project { source_dir, ..project.clone() })?;
+                git = build.git;
+            }
+            None => {
+                // 与build相同，指定git_ref时本地命令在检出的临时目录中执行，占位符使用git_ref的提交和临时目录
+                let mut source = project.clone();
+                if let Some(git_ref) = &self.args.git_ref {
+                    let (source_dir, ref_git) = Worktree::plan(&project.source_dir, git_ref, &project.name)?;
+                    term.write_line(&format!("检出 {} (提交 {}) 到临时目录：{}", git_ref, ref_git.commit, source_dir))?;
+                    source.source_dir = source_dir;
+                    git = Some(ref_git);
+                }
+                term.write_line(&format!("本地命令(在 {} 中执行)：", source.source_dir))?;
+                for cmd in self.vars(&source, &timestamp, git.as_ref()).render_cmds(&before)? {
+                    term.write_line(&format!("  {}", cmd.cmd))?;
+                }
+                for artifact in project.deploy_artifacts() {
+                    // 临时目录在部署时才检出，其中的部署文件都在执行本地命令后生成
+                    if self.args.git_ref.is_some() {
+                        pending.push(artifact.path.clone());
+                        continue;
+                    }
+                    match UploadFile::resolve_artifact(Path::new(&project.source_dir), &artifact) {
+                        Ok(resolved) => files.extend(resolved),
+                        Err(_) => pending.push(artifact.path.clone())
+                    }
+                }
+            }
+        }
+        let vars = self.vars(project, &timestamp, git.as_ref());
+        let size: u64 = files.iter().map(|x| x.size).sum();
+        term.write_line(&format!("上传文件 {} 个，共 {}：", files.len(), HumanBytes(size)))?;
+        for file in &files {
+            term.write_line(&format!("  {} ({}) -> {}", file.local.display(), HumanBytes(file.size), file.remote.display()))?;
+        }
+        let reason = if self.args.git_ref.is_some() { "在临时目录中执行本地命令后生成" } else { "当前不存在，执行本地命令后生成" };
+        for path in &pending {
+            term.write_line(&format!("  {} ({})", path, reason))?;
+        }
+
+        let batch_len = project.rolling.as_ref().map(|x| x.batch_len(targets.len())).unwrap_or(targets.len());
+        let batches: Vec<&[Server]> = targets.chunks(batch_len).collect();
+        if batches.len() > 1 {
+            for (index, batch) in batches.iter().enumerate() {
+                let names: Vec<String> = batch.iter().map(|x| x.name.clone()).collect();
+                term.write_line(&format!("第 {}/{} 批：{}", index + 1, batches.len(), names.join(", ")))?;
+            }
+        }
+        let manifest = Manifest::new(project, &self.profile(), "", &files, vec![], &timestamp, None);
+        let task = DeployTask { project: project.clone(), after, files, timestamp, manifest, vars };
+        for server in targets {
+            self.dry_run_server(&task.for_server(server)?, server)?;
+        }
+        Ok(())
+    }
+
+    /// 输出单台服务器上的部署步骤
+    fn dry_run_server(&self, task: &DeployTask, server: &Server) -> Result<()> {
+        let (project, files, after, timestamp) = (&task.project, &task.files, &task.after, &task.timestamp);
+        let term = &self.term;
+        term.write_line(&style(format!("服务器 {} ({}@{}:{})", server.name, server.user, server.host, server.port)).bold().to_string())?;
+        if !project.overridden.is_empty() {
+            term.write_line(&format!("  按服务器覆盖的配置：{}", project.overridden.join(", ")))?;
+        }
+        let mut ssh = None;
+        if self.args.verify {
+            match DeployUtil::login_server(server) {
+                Ok(session) => {
+                    term.write_line("  登录成功")?;
+                    ssh = Some(session);
+                }
+                Err(err) => term.write_line(&style(format!("  登录失败：{}", err)).red().to_string())?
+            }
+        }
+        let release = Release::new(timestamp);
+        let target_dir = match project.layout {
+            Layout::InPlace => PathBuf::from(&project.remote_dir),
+            Layout::Release => release.dir(project),
+        };
+        let mut dirs = BTreeSet::new();
+        dirs.insert(target_dir.clone());
+        for file in files {
+            if let Some(parent) = target_dir.join(&file.remote).parent() {
+                dirs.insert(parent.to_path_buf());
+            }
+        }
+        for dir in dirs {
+            match ssh.as_mut().map(|x| x.exists(&dir)).transpose()? {
+                Some(true) => {}
+                Some(false) => term.write_line(&format!("  创建目录：{}", dir.display()))?,
+                None => term.write_line(&format!("  创建目录(不存在时)：{}", dir.display()))?,
+            }
+        }
+        if let Some(ssh) = ssh.as_mut() {
+            let writable = match Path::new(&project.remote_dir).ancestors().find(|x| ssh.exists(x).unwrap_or(false)) {
+                Some(dir) => ssh.exec_output(format!("test -w {} && echo yes || echo no", quote(&dir.to_string_lossy())))?.trim() == "yes",
+                None => false
+            };
+            if !writable {
+                term.write_line(&style(format!("  {} 没有写入权限", project.remote_dir)).red().to_string())?;
+            }
+        }
 
-        match self.before_deploy(project) {
-            Err(err) => self.term.write_line(&style(format!("{}", err.to_string())).red().cyan().to_string())?,
-            Ok(()) => {}
+        let mut work_dir = None;
+        match project.layout {
+            Layout::InPlace => {
+                if project.backup_count > 0 && !project.target_name.is_empty() {
+                    term.write_line(&format!("  备份(文件存在时)：{}", Backup::new(project, timestamp).create_cmd(project)))?;
+                }
+                if let (Some(options), Some(ssh)) = (&project.sync, ssh.as_mut()) {
+                    SyncPlan::new(ssh, files, &target_dir, options)?.print(&Logger::new("  ".to_string(), ProgressBar::hidden()));
+                }
+            }
+            Layout::Release => work_dir = Some(target_dir.clone()),
+        }
+        let method = match project.upload_method {
+            UploadMethod::Scp => "scp",
+            UploadMethod::Sftp => "sftp",
         };
+        term.write_line(&format!("  上传文件到 {} ({}，上传后校验SHA-256)", target_dir.display(), method))?;
+        if project.overridden.iter().any(|x| x == "target_name") {
+            for file in files {
+                term.write_line(&format!("    {} -> {}", file.local.display(), file.remote.display()))?;
+            }
+        }
+        for cmd in after {
+            let line = match &work_dir {
+                Some(dir) => format!("cd {} && {}", quote(&dir.to_string_lossy()), cmd.cmd),
+                None => cmd.cmd.clone()
+            };
+            term.write_line(&format!("  执行：{}", line))?;
+        }
+        term.write_line(&format!("  写入部署记录：{}", Manifest::path(project, &target_dir).display()))?;
+        match project.layout {
+            Layout::InPlace if project.backup_count > 0 && !project.target_name.is_empty() =>
+                term.write_line(&format!("  清理备份，保留 {} 个", project.backup_count))?,
+            Layout::InPlace => {}
+            Layout::Release => {
+                term.write_line(&format!("  切换current：{}", release.activate_cmd(project)))?;
+                term.write_line(&format!("  清理旧版本，保留 {} 个", project.keep_releases))?;
+            }
+        }
+        if let Some(health_check) = project.rolling.as_ref().and_then(|x| x.health_check.as_ref()) {
+            term.write_line(&format!("  健康检查：{}", health_check.command()))?;
+        }
+        Ok(())
+    }
+
+    fn rollback_server(project: &Project, server: &Server, after: &[Cmd], backup: &Option<String>) -> Result<()> {
+        let logger = Logger::new(server.name.clone(), ProgressBar::hidden());
+        let mut ssh = DeployUtil::login_server(server)?;
+        ssh.logger = logger.clone();
+        match project.layout {
+            Layout::InPlace => {
+                let backups = Backup::list(&mut ssh, project)?;
+                let names: Vec<String> = backups.iter().map(|x| x.timestamp.clone()).collect();
+                let index = DeployUtil::choose_backup(server, &names, backup)?;
+                let selected = backups.get(index).unwrap();
+                logger.line(&format!("恢复备份：{}", selected.name));
+                selected.restore(&mut ssh, project)?;
+                DeployUtil::after_deploy(project, &mut ssh, after, None)?;
+            }
+            Layout::Release => {
+                let releases = Release::previous(&mut ssh, project)?;
+                let names: Vec<String> = releases.iter().map(|x| x.name.clone()).collect();
+                let index = DeployUtil::choose_backup(server, &names, backup)?;
+                let selected = releases.get(index).unwrap();
+                DeployUtil::after_deploy(project, &mut ssh, after, Some(&selected.dir(project)))?;
+                selected.activate(&mut ssh, project)?;
+                logger.line(&format!("current已切换到版本：{}", selected.name));
+            }
+        }
+        logger.line(&format!("{} 回滚完成！", server.name));
+        Ok(())
+    }
+
+    /// 选择需要恢复的备份，names按时间从新到旧排列，latest为最新的备份
+    fn choose_backup(server: &Server, names: &[String], backup: &Option<String>) -> Result<usize> {
+        if names.is_empty() {
+            return Err(anyhow!("没有可用的备份！"));
+        }
+        match backup {
+            Some(name) if name == "latest" => Ok(0),
+            Some(name) => match names.iter().position(|x| name.ends_with(x.as_str())) {
+                Some(index) => Ok(index),
+                None => Err(anyhow!("备份 {} 不存在，可选备份：{}", name, names.join(", ")))
+            },
+            None => Ok(Select::new().items(names).default(0)
+                .with_prompt(format!("请选择服务器 {} 需要恢复的备份(默认选择最新的备份)", server.name)).interact()?)
+        }
+    }
+
+    /// 使用服务器上的备份覆盖部署文件，并重新执行after命令
+    pub fn rollback(&mut self, backup: Option<String>) -> Result<()> {
+        let projects = self.config.projects.to_vec();
+        let servers = self.config.servers.to_vec();
+        let (project_index, server_index) = self.select_target(&projects, &servers)?;
+        let project = projects.get(project_index).unwrap();
+        let after = self.get_cmds(project.after.clone())?;
+        let vars = self.vars(project, &Local::now().format(TIMESTAMP_FORMAT).to_string(), None);
 
+        let mut failed = vec![];
         for index in server_index {
             let server = servers.get(index).unwrap();
-            match self.deploy(project, server) {
-                Err(err) => {
-                    self.term.write_line(&style(format!("服务器 {} 部署失败！({})", &server.name, err.to_string())).red().cyan().to_string())?;
-                    continue;
-                }
-                Ok(()) => {}
+            let project = project.for_server(&server.name);
+            let after = project.after.get(&self.profile()).unwrap_or(&after);
+            let result = vars.with_server(server, project).render_cmds(after)
+                .and_then(|after| DeployUtil::rollback_server(project, server, &after, &backup));
+            if let Err(err) = result {
+                self.term.write_line(&style(format!("服务器 {} 回滚失败！({})", &server.name, err)).red().cyan().to_string())?;
+                failed.push(server.name.clone());
             }
         }
-        exit(0);
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!("服务器 {} 回滚失败！", failed.join(", ")))
+        }
+    }
+
+    /// 列出缓存的构建，指定了项目时只列出该项目
+    pub fn builds(&self) -> Result<()> {
+        let builds = BuildCache::list(self.args.project.as_deref())?;
+        if builds.is_empty() {
+            self.term.write_line(&format!("没有缓存的构建，缓存目录：{}", BuildCache::root().display()))?;
+            return Ok(());
+        }
+        let mut rows = vec![vec!["项目".to_string(), "配置".to_string(), "构建ID".to_string(), "时间".to_string(), "文件数".to_string(), "大小".to_string()]];
+        for build in &builds {
+            rows.push(vec![build.project.clone(), build.profile.clone(), build.id.clone(), build.created.clone(),
+                           build.files.to_string(), HumanBytes(build.size).to_string()]);
+        }
+        self.print_table(&rows)
+    }
+
+    /// 按列对齐输出表格，第一行为表头，列数不足的行(例如错误信息)不参与计算列宽
+    fn print_table(&self, rows: &[Vec<String>]) -> Result<()> {
+        let columns = rows[0].len();
+        let widths: Vec<usize> = (0..columns)
+            .map(|col| rows.iter().filter(|row| row.len() == columns).map(|row| measure_text_width(&row[col])).max().unwrap_or(0))
+            .collect();
+        for (index, row) in rows.iter().enumerate() {
+            let line: Vec<String> = row.iter().zip(&widths)
+                .map(|(cell, width)| pad_str(cell, *width, Alignment::Left, None).to_string())
+                .collect();
+            let line = line.join("  ").trim_end().to_string();
+            self.term.write_line(&if index == 0 { style(line).bold().to_string() } else { line })?;
+        }
+        Ok(())
     }
-}
\ No newline at end of file
+
+    /// 读取服务器上当前生效的部署记录，未指定服务器时读取全部服务器
+    pub fn status(&self) -> Result<()> {
+        let projects = self.config.projects.to_vec();
+        let servers = self.config.servers.to_vec();
+        let project = match &self.args.project {
+            Some(name) => &projects[DeployUtil::find_project(&projects, name)?],
+            None => &projects[DeployUtil::choose_project(&projects)]
+        };
+        let server_index = match self.selected_servers(&servers, None)? {
+            Some(server_index) => server_index,
+            None => (0..servers.len()).collect()
+        };
+        let mut rows = vec![["服务器", "配置", "构建ID", "git提交", "部署时间", "部署人", "校验和"].iter().map(|x| x.to_string()).collect()];
+        for index in server_index {
+            let server = &servers[index];
+            let result = DeployUtil::login_server(server).and_then(|mut ssh| Manifest::read(&mut ssh, project.for_server(&server.name)));
+            let row = match result {
+                Ok(Some(manifest)) => vec![
+                    server.name.clone(), manifest.profile, manifest.build,
+                    manifest.git.map(|x| x.describe()).unwrap_or_default(), manifest.timestamp,
+                    format!("{}@{}", manifest.user, manifest.hostname), manifest.checksum.chars().take(12).collect(),
+                ],
+                Ok(None) => vec![server.name.clone(), style("没有部署记录").yellow().to_string()],
+                Err(err) => vec![server.name.clone(), style(format!("读取失败：{}", err)).red().to_string()],
+            };
+            rows.push(row);
+        }
+        self.print_table(&rows)
+    }
+
+    /// 列出本地部署日志中符合条件的记录
+    pub fn history(&self, mut filter: HistoryFilter) -> Result<()> {
+        filter.project = self.args.project.clone();
+        filter.servers = self.named_servers()?;
+        let entries = History::list(&filter)?;
+        if entries.is_empty() {
+            self.term.write_line(&format!("没有符合条件的部署记录，日志文件：{}", History::path().display()))?;
+            return Ok(());
+        }
+        let mut rows = vec![["时间", "项目", "配置", "构建ID", "结果", "服务器", "耗时", "错误"].iter().map(|x| x.to_string()).collect()];
+        for entry in entries {
+            let servers: Vec<String> = entry.servers.iter().map(|x| format!("{}:{}", x.server, x.status.text())).collect();
+            let error = entry.error.unwrap_or_default();
+            let error: String = error.lines().next().unwrap_or_default().chars().take(60).collect();
+            rows.push(vec![entry.time, entry.project, entry.profile, entry.build, entry.status.text().to_string(),
+                           servers.join(" "), format!("{:.1}s", entry.total_ms as f64 / 1000.0), error]);
+        }
+        self.print_table(&rows)
+    }
+}
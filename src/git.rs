@@ -0,0 +1,141 @@
+use std::env;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+
+use crate::utils::{quote, CmdUtil};
+
+/// 构建时source_dir所在git仓库的状态
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GitInfo {
+    pub commit: String,
+    /// 分离头指针时为空
+    pub branch: String,
+    /// 工作区是否有未提交的修改
+    pub dirty: bool,
+}
+
+impl GitInfo {
+    /// 读取dir所在仓库的HEAD提交和分支，不是git仓库或没有安装git时返回None，未跟踪的文件不算未提交的修改
+    pub fn read(dir: &str) -> Option<GitInfo> {
+        let cmd = GitInfo::cmd(dir);
+        let commit = cmd.output("git rev-parse HEAD").ok()?.trim().to_string();
+        if commit.is_empty() {
+            return None;
+        }
+        let branch = cmd.output("git symbolic-ref -q --short HEAD").map(|x| x.trim().to_string()).unwrap_or_default();
+        // 不计未跟踪的文件，source_dir中构建出的target_name在部署失败或keep_artifact时会保留
+        let dirty = cmd.output("git status --porcelain --untracked-files=no").map(|x| !x.trim().is_empty()).unwrap_or(false);
+        Some(GitInfo { commit, branch, dirty })
+    }
+
+    fn cmd(dir: &str) -> CmdUtil {
+        let mut cmd = CmdUtil::new();
+        cmd.change_path(dir.to_string());
+        cmd
+    }
+
+    /// 提交的前12位，有未提交的修改时加上 `-dirty`
+    pub fn describe(&self) -> String {
+        let commit = &self.commit[..self.commit.len().min(12)];
+        format!("{}{}", commit, if self.dirty { "-dirty" } else { "" })
+    }
+}
+
+/// 检出指定版本的临时工作区，位于系统临时目录，离开作用域时删除
+pub struct Worktree {
+    repo: String,
+    pub path: PathBuf,
+    /// repo相对于仓库根目录的路径，repo为仓库根目录时为空
+    prefix: String,
+}
+
+impl Worktree {
+    /// 使用 `git worktree add --detach` 将git_ref检出到临时目录
+    pub fn create(repo: &str, git_ref: &str, name: &str) -> Result<Worktree> {
+        let (path, prefix, _) = Worktree::locate(repo, git_ref, name)?;
+        let cmd = GitInfo::cmd(repo);
+        if path.exists() {
+            cmd.output(&format!("git worktree remove --force {}", quote(&path.to_string_lossy()))).ok();
+            std::fs::remove_dir_all(&path).ok();
+        }
+        cmd.output(&format!("git worktree add --detach {} {}", quote(&path.to_string_lossy()), quote(git_ref)))?;
+        Ok(Worktree { repo: repo.to_string(), path, prefix })
+    }
+
+    /// 检出后与repo对应的目录和git_ref对应的提交，不执行检出，用于输出部署计划
+    pub fn plan(repo: &str, git_ref: &str, name: &str) -> Result<(String, GitInfo)> {
+        let (path, prefix, git) = Worktree::locate(repo, git_ref, name)?;
+        Ok((path.join(prefix).to_string_lossy().to_string(), git))
+    }
+
+    /// 临时目录、repo相对于仓库根目录的路径和git_ref对应的提交
+    fn locate(repo: &str, git_ref: &str, name: &str) -> Result<(PathBuf, String, GitInfo)> {
+        let cmd = GitInfo::cmd(repo);
+        if cmd.output("git rev-parse --git-dir").is_err() {
+            return Err(anyhow!("{} 不是git仓库，无法检出 {}", repo, git_ref));
+        }
+        let commit = cmd.output(&format!("git rev-parse --verify -q {}", quote(&format!("{}^{{commit}}", git_ref))))
+            .map_err(|_| anyhow!("git版本 {} 不存在", git_ref))?.trim().to_string();
+        let path = env::temp_dir().join(format!("deploy_tool-{}-{}", name, &commit[..12]));
+        let prefix = cmd.output("git rev-parse --show-prefix")?.trim().to_string();
+        let git = GitInfo { commit, branch: String::new(), dirty: false };
+        Ok((path, prefix, git))
+    }
+
+    /// 工作区中与repo对应的目录，repo为仓库的子目录时为工作区中的同名子目录
+    pub fn dir(&self) -> String {
+        self.path.join(&self.prefix).to_string_lossy().to_string()
+    }
+}
+
+impl Drop for Worktree {
+    fn drop(&mut self) {
+        let cmd = GitInfo::cmd(&self.repo);
+        cmd.output(&format!("git worktree remove --force {}", quote(&self.path.to_string_lossy()))).ok();
+        if self.path.exists() {
+            std::fs::remove_dir_all(&self.path).ok();
+        }
+        cmd.output("git worktree prune").ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::process::Command;
+
+    use super::*;
+
+    fn git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git").args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(args).current_dir(dir).output().unwrap().status;
+        assert!(status.success(), "git {:?}", args);
+    }
+
+    #[test]
+    fn untracked_files_are_not_dirty() {
+        let repo = env::temp_dir().join(format!("deploy_tool-test-git-{}", std::process::id()));
+        std::fs::remove_dir_all(&repo).ok();
+        std::fs::create_dir_all(repo.join("sub")).unwrap();
+        git(&repo, &["init", "-q"]);
+        std::fs::write(repo.join("sub/build.sh"), "echo").unwrap();
+        git(&repo, &["add", "."]);
+        git(&repo, &["commit", "-q", "-m", "init"]);
+        let dir = repo.join("sub").to_string_lossy().to_string();
+
+        let info = GitInfo::read(&dir).unwrap();
+        assert!(!info.dirty);
+        assert_eq!(info.describe(), info.commit[..12]);
+
+        // 构建产物等未跟踪的文件
+        std::fs::write(repo.join("sub/out.jar"), "jar").unwrap();
+        assert!(!GitInfo::read(&dir).unwrap().dirty);
+
+        std::fs::write(repo.join("sub/build.sh"), "echo changed").unwrap();
+        let info = GitInfo::read(&dir).unwrap();
+        assert!(info.dirty);
+        assert!(info.describe().ends_with("-dirty"));
+        std::fs::remove_dir_all(&repo).ok();
+    }
+}
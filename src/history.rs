@@ -0,0 +1,175 @@
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::time::Instant;
+
+use anyhow::Result;
+use chrono::Local;
+
+use crate::utils::xdg_dir;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Success,
+    Failed,
+    /// 滚动部署中止后未部署的服务器
+    #[default]
+    Skipped,
+}
+
+impl Status {
+    pub fn text(&self) -> &'static str {
+        match self {
+            Status::Success => "成功",
+            Status::Failed => "失败",
+            Status::Skipped => "未部署",
+        }
+    }
+}
+
+/// 单台服务器的部署结果，耗时单位为毫秒
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ServerRecord {
+    pub server: String,
+    pub status: Status,
+    pub upload_ms: u64,
+    pub after_ms: u64,
+    pub total_ms: u64,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl ServerRecord {
+    pub fn new(server: &str) -> ServerRecord {
+        ServerRecord { server: server.to_string(), ..Default::default() }
+    }
+}
+
+/// 一次部署的记录，耗时单位为毫秒
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub time: String,
+    pub project: String,
+    pub profile: String,
+    pub build: String,
+    pub user: String,
+    pub status: Status,
+    /// 部署前置操作(构建)耗时
+    pub before_ms: u64,
+    pub total_ms: u64,
+    pub servers: Vec<ServerRecord>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl HistoryEntry {
+    pub fn new(project: &str, servers: &[String]) -> HistoryEntry {
+        HistoryEntry {
+            time: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            project: project.to_string(),
+            user: std::env::var("USER").or_else(|_| std::env::var("USERNAME")).unwrap_or_default(),
+            servers: servers.iter().map(|x| ServerRecord::new(x)).collect(),
+            ..Default::default()
+        }
+    }
+}
+
+/// history子命令的过滤条件，日期格式为 `YYYY-MM-DD`
+#[derive(Debug, Clone, Default)]
+pub struct HistoryFilter {
+    pub project: Option<String>,
+    pub servers: Vec<String>,
+    pub status: Option<Status>,
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub limit: usize,
+}
+
+impl HistoryFilter {
+    fn matches(&self, entry: &HistoryEntry) -> bool {
+        let date = entry.time.get(..10).unwrap_or_default();
+        self.project.as_ref().map(|x| *x == entry.project).unwrap_or(true)
+            && (self.servers.is_empty() || entry.servers.iter().any(|x| self.servers.contains(&x.server)))
+            && self.status.map(|x| x == entry.status).unwrap_or(true)
+            && self.since.as_ref().map(|x| date >= x.as_str()).unwrap_or(true)
+            && self.until.as_ref().map(|x| date <= x.as_str()).unwrap_or(true)
+    }
+}
+
+/// 从start到现在经过的毫秒数
+pub fn elapsed_ms(start: Instant) -> u64 {
+    start.elapsed().as_millis() as u64
+}
+
+/// 本地部署日志，每次部署追加一行JSON，不修改已有记录
+pub struct History;
+
+impl History {
+    /// 日志文件，优先使用 `$XDG_DATA_HOME/deploy_tool/history.jsonl`，其次为 `~/.local/share/deploy_tool/history.jsonl`
+    pub fn path() -> PathBuf {
+        xdg_dir("XDG_DATA_HOME", ".local/share").join("history.jsonl")
+    }
+
+    pub fn append(entry: &HistoryEntry) -> Result<()> {
+        let path = History::path();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{}", serde_json::to_string(entry)?)?;
+        Ok(())
+    }
+
+    /// 符合条件的记录，按时间从新到旧排列，最多返回filter.limit条，无法解析的行会被跳过
+    pub fn list(filter: &HistoryFilter) -> Result<Vec<HistoryEntry>> {
+        let path = History::path();
+        if !path.is_file() {
+            return Ok(vec![]);
+        }
+        let reader = BufReader::new(fs::File::open(path)?);
+        let mut entries: Vec<HistoryEntry> = reader.lines().map_while(Result::ok)
+            .filter_map(|line| serde_json::from_str(&line).ok())
+            .filter(|entry| filter.matches(entry))
+            .collect();
+        entries.reverse();
+        entries.truncate(filter.limit);
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(time: &str, project: &str, servers: &[&str], status: Status) -> HistoryEntry {
+        let servers: Vec<String> = servers.iter().map(|x| x.to_string()).collect();
+        HistoryEntry { time: time.to_string(), status, ..HistoryEntry::new(project, &servers) }
+    }
+
+    #[test]
+    fn filter_matches() {
+        let entry = entry("2021-04-18 10:15:00", "back", &["web-1", "web-2"], Status::Failed);
+        assert!(HistoryFilter::default().matches(&entry));
+        assert!(HistoryFilter { project: Some("back".to_string()), ..Default::default() }.matches(&entry));
+        assert!(!HistoryFilter { project: Some("front".to_string()), ..Default::default() }.matches(&entry));
+        assert!(HistoryFilter { servers: vec!["db".to_string(), "web-2".to_string()], ..Default::default() }.matches(&entry));
+        assert!(!HistoryFilter { servers: vec!["db".to_string()], ..Default::default() }.matches(&entry));
+        assert!(HistoryFilter { status: Some(Status::Failed), ..Default::default() }.matches(&entry));
+        assert!(!HistoryFilter { status: Some(Status::Success), ..Default::default() }.matches(&entry));
+    }
+
+    #[test]
+    fn filter_dates_are_inclusive() {
+        let entry = entry("2021-04-18 23:59:59", "back", &[], Status::Success);
+        let range = |since: &str, until: &str| HistoryFilter {
+            since: Some(since.to_string()),
+            until: Some(until.to_string()),
+            ..Default::default()
+        };
+        assert!(range("2021-04-18", "2021-04-18").matches(&entry));
+        assert!(range("2021-04-01", "2021-04-30").matches(&entry));
+        assert!(!range("2021-04-19", "2021-04-30").matches(&entry));
+        assert!(!range("2021-04-01", "2021-04-17").matches(&entry));
+    }
+}
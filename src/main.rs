@@ -4,13 +4,22 @@ extern crate regex;
 extern crate serde_derive;
 extern crate toml;
 
-use std::env;
-use std::path::Path;
-use clap::{App, Arg};
+use std::process::exit;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use dialoguer::console::{style, Term};
 
 mod utils;
 mod deploy;
 mod config;
+mod backup;
+mod artifact;
+mod sync;
+mod release;
+mod cache;
+mod git;
+mod manifest;
+mod history;
+mod vars;
 
 
 fn main() {
@@ -19,38 +28,178 @@ fn main() {
         .about("
         配置文件使用toml配置格式，private_key和password二选一，优先使用private_key登陆！
         before 和 after 有多个配置时会使用选择的配置，当只有一个配置时默认使用不需选择(多个配置时before和after的配置项名称必须相同)
+        before和after命令中可以使用占位符{name}，未定义的占位符会在加载配置时报错，${name}为shell变量不会替换，
+        {1..3}等不是变量名称的花括号原样保留，需要原样输出{name}时写作{{name}}，例如awk '{{print}}'：
+            内置变量：{target_name} {remote_dir} {source_dir} {profile} {timestamp} {git_commit} {env.环境变量名}(部署时读取，未设置时中止部署)
+            after命令还可以使用：{server.name} {server.host} {server.user} {server.port} 和服务器变量
+            自定义变量的优先级从高到低为：服务器、配置项(profiles)、项目、全局
         配置信息说明：
+            include = ['servers/*.toml', 'projects/*.toml']
+                                                    #引用其他配置文件(可选)，路径相对于当前文件，按顺序合并，
+                                                    #同一服务器、项目、分组或变量只能在一个文件中定义
+            [vars]                                  #全局变量(可选)
+                app = 'demo'
+            [groups]                                #服务器分组(可选)，成员为服务器或其他分组，可使用--group选择
+                prod-web = ['test_server']
+                prod = ['prod-web']
             [server.test_server]                    #服务器名称
                 host = '127.0.0.1'                  #服务器地址
                 port = 22                           #SSH端口
                 user = 'root'                       #服务器用户名
                 password = '1'                      #服务器密码(填写了private_key此项可为空)
                 private_key = ''                    #秘钥文件路径(免密登陆)
+                order = 1                           #排列顺序(可选，默认按配置文件中的顺序)
+                vars = { region = 'east' }          #服务器变量(可选)，只能在after命令中使用，未定义同名的全局、项目或配置项变量时，
+                                                    #项目和配置项servers允许的全部服务器都需要配置
+                tags = { region = 'cn-south' }      #服务器标签(可选)，可使用--tag region=cn-south选择
             [project.demo]                          #项目名称
                 source_dir = ''                     #项目路径
                 remote_dir = ''                     #服务器部署路径
                 target_name = ''                    #部署文件名称
+                artifacts = ['dist/**', { path = 'lib/*.jar', dest = 'lib' }]
+                                                    #部署文件、目录或通配符(可选，配置后不再上传target_name)，
+                                                    #dest为相对于remote_dir的上传位置，目录连同目录本身上传，例如dist上传为dest/dist/...，
+                                                    #通配符上传匹配的文件，保留通配符之后的路径，例如dist/**上传为dest/...
+                order = 1                           #排列顺序(可选，默认按配置文件中的顺序)
+                continue_on_error = false           #项目中命令执行失败时是否继续执行(可选，默认false)
+                parallelism = 1                     #同时部署的服务器数量(可选，默认1，可使用--parallel覆盖)
+                backup_count = 5                    #上传前备份服务器上的部署文件，保留的备份数量(可选，默认5，为0时不备份)
+                layout = 'in_place'                 #部署方式(可选)，in_place直接覆盖部署文件，release上传到remote_dir/releases/时间戳/，
+                                                    #after命令在版本目录中执行成功后将remote_dir/current切换到新版本
+                keep_releases = 5                   #release部署方式保留的版本数量(可选，默认5)
+                keep_artifact = false               #全部服务器部署完成后是否保留本地的target_name(可选，默认false，部署失败时保留)
+                upload_method = 'scp'               #上传方式(可选)，scp直接覆盖，sftp先上传到.part临时文件，支持断点续传，完成后重命名
+                upload_retries = 3                  #上传中断后重新连接并重试的次数(可选，默认3)
+                sync = { compare = 'mtime', delete = false }
+                                                    #增量同步(可选，仅in_place)，目录和通配符部署文件只上传新增和修改的文件
+                                                    #compare为mtime时比较大小和修改时间，为hash时比较SHA-256，delete删除服务器上多余的文件
+                git = { dirty = 'warn' }            #source_dir为git仓库时，工作区有未提交的修改的处理方式(可选)，
+                                                    #warn提示后继续(默认)，refuse中止部署，allow不提示
+                vars = { port = '8080' }            #项目变量(可选)
+                servers = ['web', 'c']              #允许部署的服务器或分组(可选，默认全部服务器)
+                [project.demo.profiles.test]        #配置项(可选)，名称与before和after中的配置项对应
+                 vars = { port = '80' }             #配置项变量
+                 servers = ['a']                    #该配置项允许部署的服务器或分组，与项目的servers同时配置时取交集
+                [project.demo.server.test_server]   #按服务器覆盖项目配置(可选)，可覆盖remote_dir、target_name、after、continue_on_error、
+                 remote_dir = '/opt/demo'           #layout、keep_releases、upload_method、upload_retries、sync和backup_count，
+                 after = { test = ['ls'] }          #after按配置项名称覆盖，--dry-run输出覆盖后的配置，
+                                                    #全部服务器上传同一次构建的部署文件，不能覆盖artifacts，
+                                                    #变量使用[server.test_server]中的vars，不能覆盖项目的vars
+                [project.demo.rolling]              #分批滚动部署(可选，未配置时一次部署全部服务器)
+                 batch_size = 2                     #每批服务器数量，也可使用batch_percent = 50按比例分批
+                 pause = 10                         #两批之间的间隔秒数
+                 health_check = { url = 'http://127.0.0.1:8080/health', timeout = 60, interval = 5 }
+                                                    #每批部署后在服务器上执行的健康检查，通过后才部署下一批(url和cmd二选一)
                 [project.demo.before]               #部署前操作(即文件上传前操作，例如执行项目编译压缩等操作)
-                 test = ['ls']                      #不同情况不同配置
+                 test = ['ls', { cmd = 'ls', continue_on_error = true, allowed_exit_codes = [1] }]
+                                                    #不同情况不同配置，退出码非0时中止部署
+                                                    #continue_on_error为true时继续执行，allowed_exit_codes中的退出码视为成功
                 [project.demo.after]                #部署操作(即文件上传完成后再服务器上需要完成的操作)
                  test = ['ls']
         ")
-        .arg(Arg::with_name("config").short("c").long("config").value_name("FILE").help("指定自定义配置文件"))
+        .arg(Arg::with_name("config").short("c").long("config").value_name("FILE").global(true).help("指定配置文件，未指定时依次查找环境变量DEPLOY_TOOL_CONFIG、当前及上级目录中的deploy.toml、\
+                   $XDG_CONFIG_HOME/deploy_tool/config.toml、~/.config/deploy_tool/config.toml；\
+                   只有后两个用户配置会合并同目录conf.d下的*.toml，指定的文件和deploy.toml需要使用include"))
+        .arg(Arg::with_name("project").short("p").long("project").value_name("PROJECT").global(true).help("指定部署项目，不需交互选择"))
+        .arg(Arg::with_name("server").short("s").long("server").value_name("SERVER").multiple(true).number_of_values(1).global(true)
+            .help("指定目标服务器，可重复指定或使用逗号分隔"))
+        .arg(Arg::with_name("group").short("g").long("group").value_name("GROUP").multiple(true).number_of_values(1).global(true)
+            .help("指定服务器分组，可重复指定或使用逗号分隔"))
+        .arg(Arg::with_name("tag").short("t").long("tag").value_name("KEY=VALUE").multiple(true).number_of_values(1).global(true)
+            .help("按标签选择服务器，可重复指定，需要匹配全部标签，与--server或--group同时使用时在其中过滤"))
+        .arg(Arg::with_name("all-servers").long("all-servers").conflicts_with_all(&["server", "group", "tag"]).global(true).help("部署到全部服务器"))
+        .arg(Arg::with_name("profile").long("profile").value_name("PROFILE").global(true).help("指定before和after使用的配置项"))
+        .arg(Arg::with_name("parallel").long("parallel").value_name("N").help("同时部署的服务器数量")
+            .validator(|x| match x.parse::<usize>() {
+                Ok(n) if n > 0 => Ok(()),
+                _ => Err("应为大于0的整数".to_string())
+            }))
+        .arg(Arg::with_name("keep-artifact").long("keep-artifact").help("部署完成后保留本地的部署文件"))
+        .arg(Arg::with_name("reuse-build").long("reuse-build").value_name("BUILD_ID").conflicts_with("skip-build")
+            .help("跳过部署前置操作，部署缓存中指定构建ID的构建"))
+        .arg(Arg::with_name("skip-build").long("skip-build").help("跳过部署前置操作，部署缓存中最新的构建"))
+        .arg(Arg::with_name("ref").long("ref").value_name("REF").conflicts_with_all(&["reuse-build", "skip-build"])
+            .help("将source_dir仓库中指定的分支、标签或提交检出到临时目录，在其中构建并部署"))
+        .arg(Arg::with_name("dry-run").long("dry-run").help("只输出部署计划，不执行本地命令，也不修改服务器"))
+        .arg(Arg::with_name("verify").long("verify").requires("dry-run").help("输出部署计划时登录服务器，检查认证和部署目录"))
+        .subcommand(SubCommand::with_name("config").about("配置文件相关命令").setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("path").about("显示使用的配置文件、选择的原因和查找顺序")))
+        .subcommand(SubCommand::with_name("builds").about("列出本地缓存的构建，可使用--project过滤"))
+        .subcommand(SubCommand::with_name("status").about("读取服务器上的部署记录，显示当前部署的构建，未指定服务器时显示全部服务器"))
+        .subcommand(SubCommand::with_name("history").about("查看本地部署日志，可使用--project和--server过滤")
+            .arg(Arg::with_name("status").long("status").value_name("STATUS").possible_values(&["success", "failed"])
+                .help("只显示成功或失败的部署"))
+            .arg(Arg::with_name("since").long("since").value_name("DATE").validator(validate_date).help("开始日期，格式为YYYY-MM-DD"))
+            .arg(Arg::with_name("until").long("until").value_name("DATE").validator(validate_date).help("结束日期，格式为YYYY-MM-DD"))
+            .arg(Arg::with_name("limit").long("limit").value_name("N").default_value("20")
+                .validator(|x| x.parse::<usize>().map(|_| ()).map_err(|_| "应为整数".to_string()))
+                .help("最多显示的记录数量")))
+        .subcommand(SubCommand::with_name("rollback").about("恢复服务器上的备份，并重新执行after命令")
+            .arg(Arg::with_name("backup").long("backup").value_name("BACKUP")
+                .help("备份的时间戳或文件名，latest为最新的备份，未指定时交互选择")))
         .get_matches();
 
-    let path;
-    match matchs.value_of("config") {
-        Some(config) => path = config.to_string(),
-        None => {
-            let mut config_path = env::current_exe().unwrap();
-            config_path.pop();
-            let arg: String = config_path.to_str().unwrap_or("").parse().unwrap();
-            path = match arg.contains(if cfg!(target_os = "windows") { "\\target\\debug" } else { "/target/debug" }) {
-                true => Path::new(env!("CARGO_MANIFEST_DIR")).join("config.toml").to_str().unwrap().parse().unwrap(),
-                false => Path::new(&arg).join("config.toml").to_str().unwrap().parse().unwrap()
-            }
+    if let ("config", Some(_)) = matchs.subcommand() {
+        if let Err(err) = config::ConfigPath::print(matchs.value_of("config"), &Term::stdout()) {
+            Term::stderr().write_line(&style(err.to_string()).red().to_string()).unwrap();
+            exit(1);
         }
+        return;
     }
-    let mut deploy = deploy::DeployUtil::new(path);
-    deploy.run().unwrap();
+    let config_path = match config::ConfigPath::find(matchs.value_of("config")) {
+        Ok(config_path) => config_path,
+        Err(err) => {
+            Term::stderr().write_line(&style(err.to_string()).red().to_string()).unwrap();
+            exit(1);
+        }
+    };
+    let args = deploy::DeployArgs {
+        project: matchs.value_of("project").map(|x| x.to_string()),
+        servers: split_values(&matchs, "server"),
+        groups: split_values(&matchs, "group"),
+        tags: matchs.values_of("tag").map(|values| values.map(|x| x.to_string()).collect()).unwrap_or_default(),
+        all_servers: matchs.is_present("all-servers"),
+        profile: matchs.value_of("profile").map(|x| x.to_string()),
+        parallel: matchs.value_of("parallel").map(|x| x.parse().unwrap()),
+        keep_artifact: matchs.is_present("keep-artifact"),
+        git_ref: matchs.value_of("ref").map(|x| x.to_string()),
+        dry_run: matchs.is_present("dry-run"),
+        verify: matchs.is_present("verify"),
+        reuse_build: match matchs.value_of("reuse-build") {
+            Some(id) => Some(id.to_string()),
+            None if matchs.is_present("skip-build") => Some("latest".to_string()),
+            None => None
+        },
+    };
+    let result = deploy::DeployUtil::new(config_path.path.to_string_lossy().to_string(), config_path.conf_dir(), args).and_then(|mut deploy| match matchs.subcommand() {
+        ("builds", Some(_)) => deploy.builds(),
+        ("status", Some(_)) => deploy.status(),
+        ("history", Some(sub)) => deploy.history(history::HistoryFilter {
+            status: match sub.value_of("status") {
+                Some("success") => Some(history::Status::Success),
+                Some(_) => Some(history::Status::Failed),
+                None => None
+            },
+            since: sub.value_of("since").map(|x| x.to_string()),
+            until: sub.value_of("until").map(|x| x.to_string()),
+            limit: sub.value_of("limit").unwrap().parse().unwrap(),
+            ..Default::default()
+        }),
+        ("rollback", Some(sub)) => deploy.rollback(sub.value_of("backup").map(|x| x.to_string())),
+        _ => deploy.run()
+    });
+    if let Err(err) = result {
+        Term::stderr().write_line(&style(err.to_string()).red().to_string()).unwrap();
+        exit(1);
+    }
+}
+
+/// 可重复指定或使用逗号分隔的参数值
+fn split_values(matchs: &ArgMatches, name: &str) -> Vec<String> {
+    matchs.values_of(name).map(|values| values.flat_map(|x| x.split(','))
+        .map(|x| x.trim().to_string()).filter(|x| !x.is_empty()).collect()).unwrap_or_default()
+}
+
+fn validate_date(value: String) -> Result<(), String> {
+    chrono::NaiveDate::parse_from_str(&value, "%Y-%m-%d").map(|_| ()).map_err(|_| "日期格式应为YYYY-MM-DD".to_string())
 }
@@ -0,0 +1,86 @@
+use std::env;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+
+use crate::artifact::UploadFile;
+use crate::config::{Layout, Project};
+use crate::git::GitInfo;
+use crate::utils::{CmdUtil, SshUtil};
+
+/// 部署记录，部署完成后写入服务器上部署文件所在的目录，文件名为 `.deploy-项目名称.toml`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub project: String,
+    pub profile: String,
+    /// 构建ID，与本地缓存的构建对应
+    pub build: String,
+    /// 部署文件的校验和
+    pub checksum: String,
+    pub files: usize,
+    /// 执行部署的本地用户和主机
+    pub user: String,
+    pub hostname: String,
+    pub timestamp: String,
+    /// 服务器上执行的after命令
+    pub after: Vec<String>,
+    #[serde(default)]
+    pub git: Option<GitInfo>,
+}
+
+impl Manifest {
+    pub fn new(project: &Project, profile: &str, build: &str, files: &[UploadFile], after: Vec<String>,
+               timestamp: &str, git: Option<GitInfo>) -> Manifest {
+        Manifest {
+            project: project.name.clone(),
+            profile: profile.to_string(),
+            build: build.to_string(),
+            checksum: UploadFile::checksum(files),
+            files: files.len(),
+            user: env::var("USER").or_else(|_| env::var("USERNAME")).unwrap_or_default(),
+            hostname: Manifest::hostname(),
+            timestamp: timestamp.to_string(),
+            after,
+            git,
+        }
+    }
+
+    fn hostname() -> String {
+        match env::var("HOSTNAME").or_else(|_| env::var("COMPUTERNAME")) {
+            Ok(name) if !name.is_empty() => name,
+            _ => CmdUtil::new().output("hostname").map(|x| x.trim().to_string()).unwrap_or_default()
+        }
+    }
+
+    fn file_name(project: &Project) -> String {
+        format!(".deploy-{}.toml", project.name)
+    }
+
+    /// dir目录中的部署记录路径，in_place方式为remote_dir，release方式为版本目录
+    pub fn path(project: &Project, dir: &Path) -> PathBuf {
+        dir.join(Manifest::file_name(project))
+    }
+
+    pub fn write(&self, ssh: &mut SshUtil, project: &Project, dir: &Path) -> Result<()> {
+        ssh.write_file(&Manifest::path(project, dir), &toml::to_string(self)?)
+    }
+
+    /// 当前生效的部署记录路径，release方式读取current指向的版本目录
+    pub fn current_path(project: &Project) -> PathBuf {
+        let dir = Path::new(&project.remote_dir);
+        match project.layout {
+            Layout::InPlace => dir.join(Manifest::file_name(project)),
+            Layout::Release => dir.join("current").join(Manifest::file_name(project)),
+        }
+    }
+
+    /// 读取当前生效的部署记录，没有部署记录时返回None
+    pub fn read(ssh: &mut SshUtil, project: &Project) -> Result<Option<Manifest>> {
+        let path = Manifest::current_path(project);
+        match ssh.read_file(&path)? {
+            Some(content) => toml::from_str(&content).map(Some)
+                .map_err(|err| anyhow!("部署记录 {} 格式错误：{}", path.display(), err)),
+            None => Ok(None)
+        }
+    }
+}
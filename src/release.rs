@@ -0,0 +1,95 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use regex::Regex;
+
+use crate::config::Project;
+use crate::utils::{quote, SshUtil};
+
+/// 版本目录部署，每次部署上传到 `remote_dir/releases/时间戳/`，`remote_dir/current` 指向当前版本
+#[derive(Debug, Clone)]
+pub struct Release {
+    pub name: String,
+}
+
+impl Release {
+    fn releases_dir(project: &Project) -> PathBuf {
+        Path::new(&project.remote_dir).join("releases")
+    }
+
+    fn current_link(project: &Project) -> PathBuf {
+        Path::new(&project.remote_dir).join("current")
+    }
+
+    pub fn new(name: &str) -> Release {
+        Release { name: name.to_string() }
+    }
+
+    pub fn dir(&self, project: &Project) -> PathBuf {
+        Release::releases_dir(project).join(&self.name)
+    }
+
+    /// current当前指向的版本
+    pub fn current(ssh: &mut SshUtil, project: &Project) -> Result<Option<Release>> {
+        let link = ssh.read_link(&Release::current_link(project))?;
+        Ok(link.and_then(|x| Path::new(&x).file_name().map(|name| Release::new(&name.to_string_lossy()))))
+    }
+
+    /// 服务器上的全部版本，按时间从新到旧排列
+    pub fn list(ssh: &mut SshUtil, project: &Project) -> Result<Vec<Release>> {
+        let dir = Release::releases_dir(project);
+        if !ssh.exists(&dir)? {
+            return Ok(vec![]);
+        }
+        let reg = Regex::new(r"^\d{8}-\d{6}$")?;
+        let mut releases: Vec<Release> = ssh.list_dir(&dir)?.into_iter()
+            .filter(|name| reg.is_match(name))
+            .map(|name| Release { name })
+            .collect();
+        releases.sort_by(|a, b| b.name.cmp(&a.name));
+        Ok(releases)
+    }
+
+    /// 先创建临时链接再重命名为current，切换过程中current始终可用
+    pub fn activate_cmd(&self, project: &Project) -> String {
+        let link = Release::current_link(project);
+        let tmp_link = Path::new(&project.remote_dir).join(format!("current.{}", self.name));
+        let target = Path::new("releases").join(&self.name);
+        format!("ln -sfn {} {} && mv -Tf {} {}",
+                quote(&target.to_string_lossy()), quote(&tmp_link.to_string_lossy()),
+                quote(&tmp_link.to_string_lossy()), quote(&link.to_string_lossy()))
+    }
+
+    pub fn activate(&self, ssh: &mut SshUtil, project: &Project) -> Result<()> {
+        ssh.exec(self.activate_cmd(project), &[])
+    }
+
+    /// 比current旧的版本，按时间从新到旧排列，用于回滚；比current新的版本是部署失败、未切换过的版本
+    pub fn previous(ssh: &mut SshUtil, project: &Project) -> Result<Vec<Release>> {
+        let current = Release::current(ssh, project)?.map(|x| x.name);
+        Ok(Release::list(ssh, project)?.into_iter()
+            .filter(|x| current.as_ref().map(|current| x.name < *current).unwrap_or(true))
+            .collect())
+    }
+
+    /// 删除版本目录
+    pub fn remove(&self, ssh: &mut SshUtil, project: &Project) -> Result<()> {
+        ssh.exec(format!("rm -rf {}", quote(&self.dir(project).to_string_lossy())), &[])
+    }
+
+    /// 删除比current新的失败版本，current和之前的版本只保留最近的keep_releases个
+    pub fn prune(ssh: &mut SshUtil, project: &Project) -> Result<()> {
+        let current = match Release::current(ssh, project)? {
+            Some(current) => current.name,
+            None => return Ok(())
+        };
+        let releases = Release::list(ssh, project)?;
+        let failed = releases.iter().filter(|x| x.name > current);
+        let old = releases.iter().filter(|x| x.name <= current).skip(project.keep_releases);
+        for release in failed.chain(old) {
+            ssh.logger.line(&format!("删除版本：{}", release.name));
+            release.remove(ssh, project)?;
+        }
+        Ok(())
+    }
+}
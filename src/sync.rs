@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+use crate::artifact::UploadFile;
+use crate::config::{Compare, SyncOptions};
+use crate::utils::{quote, Logger, SshUtil};
+
+/// 服务器上的文件信息，hash方式为SHA-256，mtime方式为大小和修改时间
+enum RemoteFile {
+    Stat { size: u64, mtime: u64 },
+    Hash(String),
+}
+
+/// 增量同步计划，目录和通配符部署文件只上传新增和修改的文件
+pub struct SyncPlan {
+    /// 需要上传的文件，单个文件的部署文件总是上传
+    pub upload: Vec<UploadFile>,
+    pub added: Vec<PathBuf>,
+    pub changed: Vec<PathBuf>,
+    pub deleted: Vec<PathBuf>,
+    pub unchanged: usize,
+    /// 部署目录本身不删除多余文件
+    pub skipped_delete: bool,
+}
+
+impl SyncPlan {
+    /// 列出dir下的全部文件，路径相对于dir，目录不存在时返回空
+    fn list_remote(ssh: &mut SshUtil, dir: &Path, compare: Compare) -> Result<HashMap<PathBuf, RemoteFile>> {
+        let dir = quote(&dir.to_string_lossy());
+        let mut files = HashMap::new();
+        match compare {
+            Compare::Mtime => {
+                let output = ssh.exec_output(format!("cd {} 2>/dev/null && find . -type f -printf '%s %T@ %P\\n' || true", dir))?;
+                for line in output.lines() {
+                    let items: Vec<&str> = line.splitn(3, ' ').collect();
+                    if let [size, mtime, path] = items.as_slice() {
+                        let size = size.parse().unwrap_or(0);
+                        let mtime = mtime.split('.').next().unwrap_or("").parse().unwrap_or(0);
+                        files.insert(PathBuf::from(path), RemoteFile::Stat { size, mtime });
+                    }
+                }
+            }
+            Compare::Hash => {
+                let output = ssh.exec_output(format!("cd {} 2>/dev/null && find . -type f -exec sha256sum {{}} + || true", dir))?;
+                for line in output.lines() {
+                    if let Some((hash, path)) = line.split_once("  ") {
+                        let path = path.trim_start_matches("./");
+                        files.insert(PathBuf::from(path), RemoteFile::Hash(hash.to_string()));
+                    }
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    fn same(file: &UploadFile, remote: &RemoteFile) -> bool {
+        match remote {
+            RemoteFile::Stat { size, mtime } => *size == file.size && *mtime == file.mtime,
+            RemoteFile::Hash(hash) => *hash == file.sha256,
+        }
+    }
+
+    pub fn new(ssh: &mut SshUtil, files: &[UploadFile], target_dir: &Path, options: &SyncOptions) -> Result<SyncPlan> {
+        let mut roots: Vec<PathBuf> = vec![];
+        for root in files.iter().filter_map(|x| x.root.clone()) {
+            if !roots.contains(&root) {
+                roots.push(root);
+            }
+        }
+        let mut remote = HashMap::new();
+        for root in &roots {
+            for (path, file) in SyncPlan::list_remote(ssh, &target_dir.join(root), options.compare)? {
+                remote.insert(root.join(path), file);
+            }
+        }
+
+        let mut plan = SyncPlan { upload: vec![], added: vec![], changed: vec![], deleted: vec![], unchanged: 0, skipped_delete: false };
+        for file in files {
+            if file.root.is_none() {
+                plan.upload.push(file.clone());
+                continue;
+            }
+            match remote.remove(&file.remote) {
+                None => {
+                    plan.added.push(file.remote.clone());
+                    plan.upload.push(file.clone());
+                }
+                Some(remote_file) => {
+                    if SyncPlan::same(file, &remote_file) {
+                        plan.unchanged += 1;
+                    } else {
+                        plan.changed.push(file.remote.clone());
+                        plan.upload.push(file.clone());
+                    }
+                }
+            }
+        }
+        if options.delete {
+            plan.skipped_delete = roots.iter().any(|x| x.as_os_str().is_empty());
+            let delete_roots: Vec<&PathBuf> = roots.iter().filter(|x| !x.as_os_str().is_empty()).collect();
+            plan.deleted = remote.into_keys()
+                .filter(|path| delete_roots.iter().any(|root| path.starts_with(root)))
+                .collect();
+            plan.deleted.sort();
+        }
+        Ok(plan)
+    }
+
+    /// 同步前输出新增、修改和删除的文件
+    pub fn print(&self, logger: &Logger) {
+        logger.line(&format!("同步文件：新增 {} 个，修改 {} 个，删除 {} 个，未变化 {} 个",
+                             self.added.len(), self.changed.len(), self.deleted.len(), self.unchanged));
+        for path in &self.added {
+            logger.line(&format!("+ {}", path.display()));
+        }
+        for path in &self.changed {
+            logger.line(&format!("~ {}", path.display()));
+        }
+        for path in &self.deleted {
+            logger.line(&format!("- {}", path.display()));
+        }
+        if self.skipped_delete {
+            logger.line("未配置dest的部署文件直接上传到部署目录，不删除部署目录中的多余文件");
+        }
+    }
+
+    pub fn delete(&self, ssh: &mut SshUtil, target_dir: &Path) -> Result<()> {
+        for path in &self.deleted {
+            ssh.remove_file(&target_dir.join(path))?;
+        }
+        Ok(())
+    }
+}
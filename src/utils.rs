@@ -1,31 +1,116 @@
-use std::fs::{File, OpenOptions};
-use std::io::{BufRead, BufReader, Read, Write};
+use std::env;
+use std::fmt;
+use std::fs::{File, Metadata, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
 use std::net::TcpStream;
-use std::path::Path;
-use std::process::{Command, Stdio};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::UNIX_EPOCH;
 
 use anyhow::{anyhow, Result};
 use dialoguer::console::Term;
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
+use sha2::{Digest, Sha256};
 use ssh2::*;
 
-fn status(code: i32) -> Result<()> {
-    if code == 1 || code == 2 || code == 126 || code == 127 || code == 128 {
-        Err(anyhow!("命令执行错误！"))
-    } else {
+/// 命令执行失败的原因，包含命令内容、退出码和错误输出
+#[derive(Debug)]
+pub struct CmdError {
+    pub cmd: String,
+    pub code: Option<i32>,
+    pub signal: Option<String>,
+    pub stderr: String,
+}
+
+impl fmt::Display for CmdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.signal, self.code) {
+            (Some(signal), _) => write!(f, "命令 {} 被信号 {} 终止", self.cmd, signal)?,
+            (None, Some(code)) => write!(f, "命令 {} 执行失败，退出码 {}", self.cmd, code)?,
+            (None, None) => write!(f, "命令 {} 执行失败，未获取到退出码", self.cmd)?,
+        }
+        let stderr = self.stderr.trim();
+        if !stderr.is_empty() {
+            let lines: Vec<&str> = stderr.lines().collect();
+            let tail = &lines[lines.len().saturating_sub(STDERR_LINES)..];
+            write!(f, "\n{}", tail.join("\n"))?;
+        }
         Ok(())
     }
 }
 
+impl std::error::Error for CmdError {}
+
+/// 错误信息中保留的错误输出行数
+const STDERR_LINES: usize = 20;
+
+/// 退出码为0或在允许的退出码列表中时视为成功，被信号终止时视为失败
+fn status(cmd: &str, code: Option<i32>, signal: Option<String>, allowed_exit_codes: &[i32], stderr: String) -> Result<()> {
+    match (&signal, code) {
+        (None, Some(code)) if code == 0 || allowed_exit_codes.contains(&code) => Ok(()),
+        _ => Err(CmdError { cmd: cmd.to_string(), code, signal, stderr }.into())
+    }
+}
+
+/// 输出日志，多台服务器同时部署时在每行前加上服务器名称，并输出在进度条上方
+#[derive(Clone)]
+pub struct Logger {
+    pub prefix: String,
+    pub pb: ProgressBar,
+}
+
+impl Logger {
+    pub fn new(prefix: String, pb: ProgressBar) -> Logger {
+        Logger { prefix, pb }
+    }
+
+    pub fn stdout() -> Logger {
+        Logger { prefix: String::new(), pb: ProgressBar::hidden() }
+    }
+
+    pub fn line(&self, msg: &str) {
+        let lines: Vec<String> = msg.lines().map(|line| match self.prefix.is_empty() {
+            true => line.to_string(),
+            false => format!("[{}] {}", self.prefix, line)
+        }).collect();
+        if lines.is_empty() {
+            return;
+        }
+        // 进度条输出到stderr，stderr不是终端时进度条不显示，直接输出到stdout
+        if self.pb.is_hidden() || !Term::stderr().features().is_attended() {
+            Term::stdout().write_line(&lines.join("\n")).ok();
+        } else {
+            self.pb.println(lines.join("\n"));
+        }
+    }
+
+    /// 执行命令时显示的状态
+    pub fn status(&self, msg: &str) {
+        self.pb.set_style(ProgressStyle::default_spinner().template("{prefix:.bold} {spinner:.green} {wide_msg}"));
+        self.pb.set_message(msg);
+    }
+
+    /// 上传文件时显示的进度条
+    pub fn progress(&self, len: u64) {
+        self.pb.set_style(ProgressStyle::default_bar()
+            .template("{prefix:.bold} {spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta}) {msg}")
+            .progress_chars("#>-"));
+        self.pb.set_length(len);
+        self.pb.set_position(0);
+    }
+}
+
 #[derive(Clone)]
 pub struct SshUtil {
     pub session: Session,
+    pub logger: Logger,
 }
 
 impl SshUtil {
     pub fn new(host: String, port: i64) -> Result<SshUtil> {
         let mut session = Session::new()?;
-        let mut server = String::from(host);
+        let mut server = host;
         server.push(':');
         server.push_str(&port.to_string());
         match TcpStream::connect(server) {
@@ -34,7 +119,7 @@ impl SshUtil {
                 session.set_compress(true);
                 session.set_timeout(30000);
                 session.handshake()?;
-                Ok(SshUtil { session })
+                Ok(SshUtil { session, logger: Logger::stdout() })
             }
             Err(err) => Err(anyhow!(err.to_string()))
         }
@@ -60,31 +145,48 @@ impl SshUtil {
         Ok(self.session.userauth_pubkey_memory(&name, None, &key, None)?)
     }
 
-    pub fn exec(&mut self, cmd: String) -> Result<()> {
-        let term = Term::stdout();
-        term.write_line(&format!("执行命令：{}", cmd))?;
+    pub fn exec(&mut self, cmd: String, allowed_exit_codes: &[i32]) -> Result<()> {
+        self.logger.line(&format!("执行命令：{}", cmd));
+        self.logger.status(&format!("执行命令：{}", cmd));
+        let (result, stderr) = self.run(&cmd, allowed_exit_codes)?;
+        self.logger.line(&result);
+        self.logger.line(&stderr);
+        Ok(())
+    }
+
+    /// 执行命令并返回标准输出，不输出日志
+    pub fn exec_output(&mut self, cmd: String) -> Result<String> {
+        let (result, _) = self.run(&cmd, &[])?;
+        Ok(result)
+    }
+
+    fn run(&mut self, cmd: &str, allowed_exit_codes: &[i32]) -> Result<(String, String)> {
         let mut channel = self.session.channel_session()?;
-        channel.exec(&cmd)?;
+        channel.exec(cmd)?;
         let mut result = String::new();
         channel.read_to_string(&mut result)?;
-        term.write_line(&format!("{}", result))?;
-        result.clear();
-        channel.stderr().read_to_string(&mut result)?;
-        term.write_line(&format!("{}", result))?;
+        let mut stderr = String::new();
+        channel.stderr().read_to_string(&mut stderr)?;
         channel.send_eof()?;
         channel.wait_eof()?;
         channel.wait_close()?;
 
+        let signal = channel.exit_signal()?.exit_signal;
         let status_code = channel.exit_status()?;
-        Ok(status(status_code)?)
+        if let Err(err) = status(cmd, Some(status_code), signal, allowed_exit_codes, stderr.clone()) {
+            self.logger.line(&result);
+            return Err(err);
+        }
+        Ok((result, stderr))
     }
 
-    pub fn upload_file(&mut self, file_path: &Path, remote_path: &Path) -> Result<()> {
-        let term = Term::stdout();
-        term.write_line("开始文件上传！")?;
+    pub fn upload_file(&mut self, file_path: &Path, remote_path: &Path, mode: i32) -> Result<()> {
+        self.logger.line(&format!("开始文件上传：{}", remote_path.display()));
         let mut fs = File::open(file_path)?;
-        let len = fs.metadata()?.len();
-        let remote_file = self.session.scp_send(remote_path, 0o644, len.clone(), None);
+        let metadata = fs.metadata()?;
+        let len = metadata.len();
+        let mtime = modified_secs(&metadata);
+        let remote_file = self.session.scp_send(remote_path, mode, len, Some((mtime, mtime)));
         match remote_file {
             Err(e) => Err(anyhow!(e.to_string())),
             _ => {
@@ -94,17 +196,19 @@ impl SshUtil {
                 }) as usize];
                 let mut remote_file = remote_file?;
 
-                let pb = ProgressBar::new(len.clone());
-                pb.set_style(ProgressStyle::default_bar()
-                    .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})\n{msg}")
-                    .progress_chars("#>-"));
+                self.logger.progress(len);
+                self.logger.pb.set_message("文件上传中");
                 let mut pos = 0;
                 while pos < len {
-                    pos = pos + fs.read(&mut buf.as_mut_slice())? as u64;
-                    remote_file.write_all(&buf.as_slice())?;
-                    pb.set_position(pos);
+                    let size = fs.read(buf.as_mut_slice())?;
+                    if size == 0 {
+                        return Err(anyhow!("文件 {} 读取不完整", file_path.display()));
+                    }
+                    remote_file.write_all(&buf[..size])?;
+                    pos += size as u64;
+                    self.logger.pb.set_position(pos);
                 }
-                pb.finish_with_message("文件上传完成!");
+                self.logger.line("文件上传完成!");
                 remote_file.send_eof()?;
                 remote_file.wait_eof()?;
                 remote_file.close()?;
@@ -114,19 +218,154 @@ impl SshUtil {
         }
     }
 
+    /// 通过SFTP上传到part_path临时文件，临时文件已存在时从断点继续上传，完成后重命名为remote_path
+    pub fn upload_file_resumable(&mut self, file_path: &Path, remote_path: &Path, mode: i32, part_path: &Path) -> Result<()> {
+        let mut fs = File::open(file_path)?;
+        let metadata = fs.metadata()?;
+        let len = metadata.len();
+        let mtime = modified_secs(&metadata);
+        let sftp = self.session.sftp()?;
+        let mut offset = sftp.stat(part_path).ok().and_then(|x| x.size).unwrap_or(0);
+        if offset > len {
+            sftp.unlink(part_path)?;
+            offset = 0;
+        }
+        if offset > 0 {
+            self.logger.line(&format!("继续上传：{}，已上传 {}", remote_path.display(), HumanBytes(offset)));
+        } else {
+            self.logger.line(&format!("开始文件上传：{}", remote_path.display()));
+        }
+
+        let mut remote_file = sftp.open_mode(part_path, OpenFlags::WRITE | OpenFlags::CREATE, mode, OpenType::File)?;
+        remote_file.seek(SeekFrom::Start(offset))?;
+        fs.seek(SeekFrom::Start(offset))?;
+        self.logger.progress(len);
+        self.logger.pb.set_message("文件上传中");
+        self.logger.pb.set_position(offset);
+        let mut buf = vec![0; 32 * 1024];
+        let mut pos = offset;
+        while pos < len {
+            let size = fs.read(buf.as_mut_slice())?;
+            if size == 0 {
+                return Err(anyhow!("文件 {} 读取不完整", file_path.display()));
+            }
+            remote_file.write_all(&buf[..size])?;
+            pos += size as u64;
+            self.logger.pb.set_position(pos);
+        }
+        drop(remote_file);
+
+        let stat = FileStat { size: None, uid: None, gid: None, perm: Some(mode as u32), atime: Some(mtime), mtime: Some(mtime) };
+        sftp.setstat(part_path, stat)?;
+        self.exec_output(format!("mv -f {} {}", quote(&part_path.to_string_lossy()), quote(&remote_path.to_string_lossy())))?;
+        self.logger.line("文件上传完成!");
+        Ok(())
+    }
+
+    /// 检查目录是否存在，不存在时逐级创建
     pub fn check_dir(&mut self, path: &Path) -> Result<()> {
         match self.session.sftp() {
             Ok(sftp) => {
-                match sftp.stat(path) {
-                    Err(_e) => {
-                        Ok(sftp.mkdir(path, 0o644)?)
+                let mut dirs: Vec<&Path> = path.ancestors().take_while(|x| sftp.stat(x).is_err()).collect();
+                dirs.reverse();
+                for dir in dirs {
+                    if !dir.as_os_str().is_empty() {
+                        sftp.mkdir(dir, 0o755)?;
                     }
-                    Ok(_stat) => Ok(())
                 }
+                Ok(())
             }
             Err(err) => Err(anyhow!(err.to_string()))
         }
     }
+
+    pub fn exists(&mut self, path: &Path) -> Result<bool> {
+        let sftp = self.session.sftp()?;
+        Ok(sftp.stat(path).is_ok())
+    }
+
+    /// 返回目录下的文件名称
+    pub fn list_dir(&mut self, path: &Path) -> Result<Vec<String>> {
+        let sftp = self.session.sftp()?;
+        Ok(sftp.readdir(path)?.iter()
+            .filter_map(|(path, _)| path.file_name().map(|x| x.to_string_lossy().to_string()))
+            .collect())
+    }
+
+    /// 在服务器上计算文件的SHA-256，没有sha256sum时使用shasum
+    pub fn sha256(&mut self, path: &Path) -> Result<String> {
+        let path = quote(&path.to_string_lossy());
+        let output = self.exec_output(format!("sha256sum {} 2>/dev/null || shasum -a 256 {}", path, path))?;
+        match output.split_whitespace().next() {
+            Some(hash) => Ok(hash.to_lowercase()),
+            None => Err(anyhow!("无法计算文件 {} 的SHA-256", path))
+        }
+    }
+
+    /// 读取符号链接指向的路径，不是符号链接时返回None
+    pub fn read_link(&mut self, path: &Path) -> Result<Option<String>> {
+        let sftp = self.session.sftp()?;
+        match sftp.lstat(path) {
+            Ok(stat) if stat.file_type().is_symlink() => Ok(Some(sftp.readlink(path)?.to_string_lossy().to_string())),
+            _ => Ok(None)
+        }
+    }
+
+    /// 写入文本文件，文件已存在时覆盖
+    pub fn write_file(&mut self, path: &Path, content: &str) -> Result<()> {
+        let sftp = self.session.sftp()?;
+        let mut remote_file = sftp.create(path)?;
+        remote_file.write_all(content.as_bytes())?;
+        Ok(())
+    }
+
+    /// 读取文本文件，文件不存在时返回None
+    pub fn read_file(&mut self, path: &Path) -> Result<Option<String>> {
+        let sftp = self.session.sftp()?;
+        let mut remote_file = match sftp.open(path) {
+            Ok(file) => file,
+            Err(_) => return Ok(None)
+        };
+        let mut content = String::new();
+        remote_file.read_to_string(&mut content)?;
+        Ok(Some(content))
+    }
+
+    pub fn remove_file(&mut self, path: &Path) -> Result<()> {
+        let sftp = self.session.sftp()?;
+        Ok(sftp.unlink(path)?)
+    }
+}
+
+/// 文件的修改时间(秒)
+pub fn modified_secs(metadata: &Metadata) -> u64 {
+    metadata.modified().ok()
+        .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+        .map(|x| x.as_secs())
+        .unwrap_or(0)
+}
+
+/// 计算本地文件的SHA-256
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut fs = File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut fs, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// 本工具的用户目录，优先使用环境变量var指定的目录，其次为 `~/default`，例如 `~/.cache/deploy_tool`
+pub fn xdg_dir(var: &str, default: &str) -> PathBuf {
+    let base = match (env::var_os(var), env::var_os("HOME")) {
+        (Some(dir), _) if !dir.is_empty() => PathBuf::from(dir),
+        (_, Some(home)) if !home.is_empty() => Path::new(&home).join(default),
+        _ => env::temp_dir(),
+    };
+    base.join("deploy_tool")
+}
+
+/// 用单引号包裹shell参数
+pub fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
 }
 
 #[derive(Clone)]
@@ -143,41 +382,71 @@ impl CmdUtil {
         self.current_dir = path;
     }
 
-    pub fn exec(&self, cmd: String) -> Result<()> {
+    pub fn exec(&self, cmd: String, allowed_exit_codes: &[i32]) -> Result<()> {
         let term = Term::stdout();
         term.write_line(&format!("执行命令：{}", cmd))?;
-        let mut out;
-        if cfg!(target_os = "windows") {
-            out = match self.current_dir.len() {
-                0 => Command::new("powershell").stdin(Stdio::piped()).stdout(Stdio::piped()).arg(cmd).spawn()?,
-                _ => {
-                    Command::new("powershell").current_dir(&self.current_dir).stdin(Stdio::piped()).stdout(Stdio::piped()).arg(cmd).spawn()?
-                }
-            };
+        let mut command = if cfg!(target_os = "windows") {
+            let mut command = Command::new("powershell");
+            command.arg(&cmd);
+            command
         } else {
-            out = match self.current_dir.len() {
-                0 => Command::new("sh").stdin(Stdio::piped()).stdout(Stdio::piped()).arg("-c").arg(cmd).spawn()?,
-                _ => Command::new("sh").current_dir(&self.current_dir).stdin(Stdio::piped()).stdout(Stdio::piped()).arg("-c").arg(cmd).spawn()?
-            };
-        }
-        let mut buf_reader = BufReader::new(out.stdout.take().unwrap());
-        let mut line = String::new();
-        let get_last_line = |lines: String| -> String {
-            let array: Vec<&str> = lines.split("\n").collect();
-            match array.len() {
-                0 => lines,
-                1 => array.get(0).unwrap().to_string(),
-                2 => array.get(1).unwrap().to_string(),
-                _ => array.get(array.len() - 2).unwrap().to_string()
-            }
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(&cmd);
+            command
         };
-        loop {
-            match buf_reader.read_line(&mut line) {
-                Ok(0) => break,
-                _ => term.write_line(&format!("{}", get_last_line(line.clone())))?
-            };
+        if !self.current_dir.is_empty() {
+            command.current_dir(&self.current_dir);
+        }
+        let mut out = command.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+
+        let err_reader = BufReader::new(out.stderr.take().unwrap());
+        let stderr_handle = thread::spawn(move || {
+            let term = Term::stderr();
+            let mut stderr = String::new();
+            for line in err_reader.lines().map_while(Result::ok) {
+                term.write_line(&line).ok();
+                stderr.push_str(&line);
+                stderr.push('\n');
+            }
+            stderr
+        });
+        let buf_reader = BufReader::new(out.stdout.take().unwrap());
+        for line in buf_reader.lines() {
+            term.write_line(&line?)?;
+        }
+        let stderr = stderr_handle.join().unwrap_or_default();
+        let exit_status = out.wait()?;
+        status(&cmd, exit_status.code(), CmdUtil::signal(&exit_status), allowed_exit_codes, stderr)
+    }
+
+    /// 执行命令并返回标准输出，不输出日志
+    pub fn output(&self, cmd: &str) -> Result<String> {
+        let mut command = if cfg!(target_os = "windows") {
+            let mut command = Command::new("powershell");
+            command.arg(cmd);
+            command
+        } else {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(cmd);
+            command
         };
-        let status_code = out.wait().unwrap().code().unwrap();
-        Ok(status(status_code)?)
+        if !self.current_dir.is_empty() {
+            command.current_dir(&self.current_dir);
+        }
+        let output = command.stdin(Stdio::null()).output()?;
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        status(cmd, output.status.code(), CmdUtil::signal(&output.status), &[], stderr)?;
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
     }
-}
\ No newline at end of file
+
+    #[cfg(unix)]
+    fn signal(exit_status: &ExitStatus) -> Option<String> {
+        use std::os::unix::process::ExitStatusExt;
+        exit_status.signal().map(|x| x.to_string())
+    }
+
+    #[cfg(not(unix))]
+    fn signal(_exit_status: &ExitStatus) -> Option<String> {
+        None
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use std::env;
+
+use anyhow::{anyhow, Result};
+use indexmap::IndexMap;
+use regex::Regex;
+
+use crate::config::{Cmd, Project, Server};
+
+/// 本地和服务器上的命令都可以使用的内置变量
+pub const BUILTINS: &[&str] = &["target_name", "remote_dir", "source_dir", "profile", "timestamp", "git_commit"];
+
+/// 只能在after命令中使用的服务器内置变量
+pub const SERVER_BUILTINS: &[&str] = &["server.name", "server.host", "server.user", "server.port"];
+
+/// 占位符 `{name}`，名称以字母或下划线开头，可带一级 `.` 前缀，例如 `{server.name}`；
+/// `${name}` 为shell变量，`{{...}}` 转义为 `{...}`，其他花括号(例如 `{1..3}`)原样保留
+fn regex() -> Regex {
+    Regex::new(r"(\$?)\{(\{[^{}]*\}|[A-Za-z_][A-Za-z0-9_\-]*(?:\.[A-Za-z0-9_\-]+)?)\}").unwrap()
+}
+
+/// 匹配结果是否为占位符，shell变量和转义的花括号不是占位符
+fn is_placeholder(caps: &regex::Captures) -> bool {
+    caps[1].is_empty() && !caps[2].starts_with('{')
+}
+
+/// 命令中的全部占位符名称
+pub fn placeholders(cmd: &str) -> Vec<String> {
+    regex().captures_iter(cmd)
+        .filter(is_placeholder)
+        .map(|x| x[2].to_string())
+        .collect()
+}
+
+/// 自定义变量名称以字母或下划线开头，只能包含字母、数字、下划线和横线，不能与内置变量重名
+pub fn check_name(name: &str) -> Result<()> {
+    let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid {
+        return Err(anyhow!("变量名称 {} 只能以字母或下划线开头，包含字母、数字、下划线和横线", name));
+    }
+    if BUILTINS.contains(&name) {
+        return Err(anyhow!("变量名称 {} 与内置变量重名", name));
+    }
+    Ok(())
+}
+
+/// 命令中占位符的取值，优先级从高到低为：服务器、配置(profile)、项目、全局
+#[derive(Debug, Clone, Default)]
+pub struct Vars {
+    values: HashMap<String, String>,
+}
+
+impl Vars {
+    pub fn new(global: &IndexMap<String, String>, project: &Project, profile: &str, timestamp: &str, git_commit: &str) -> Vars {
+        let mut vars = Vars::default();
+        vars.extend(global);
+        vars.extend(&project.vars);
+        if let Some(profile) = project.profiles.get(profile) {
+            vars.extend(&profile.vars);
+        }
+        vars.insert("target_name", &project.target_name);
+        vars.insert("remote_dir", &project.remote_dir);
+        vars.insert("source_dir", &project.source_dir);
+        vars.insert("profile", profile);
+        vars.insert("timestamp", timestamp);
+        vars.insert("git_commit", git_commit);
+        vars
+    }
+
+    /// 加入服务器的变量和内置变量，用于after命令，project为按服务器覆盖后的项目配置
+    pub fn with_server(&self, server: &Server, project: &Project) -> Vars {
+        let mut vars = self.clone();
+        vars.extend(&server.vars);
+        vars.insert("remote_dir", &project.remote_dir);
+        vars.insert("target_name", &project.target_name);
+        vars.insert("server.name", &server.name);
+        vars.insert("server.host", &server.host);
+        vars.insert("server.user", &server.user);
+        vars.insert("server.port", &server.port.to_string());
+        vars
+    }
+
+    pub fn insert(&mut self, name: &str, value: &str) {
+        self.values.insert(name.to_string(), value.to_string());
+    }
+
+    pub fn extend(&mut self, vars: &IndexMap<String, String>) {
+        for (name, value) in vars {
+            self.insert(name, value);
+        }
+    }
+
+    /// 替换命令中的占位符，`{env.VAR}` 读取本地环境变量，`{{...}}` 替换为 `{...}`，未定义的占位符返回错误
+    pub fn render(&self, cmd: &str) -> Result<String> {
+        let mut error = None;
+        let result = regex().replace_all(cmd, |caps: &regex::Captures| {
+            if !caps[1].is_empty() {
+                return caps[0].to_string();
+            }
+            if caps[2].starts_with('{') {
+                return caps[2].to_string();
+            }
+            let name = &caps[2];
+            let value = match name.strip_prefix("env.") {
+                Some(var) => env::var(var).ok(),
+                None => self.values.get(name).cloned()
+            };
+            value.unwrap_or_else(|| {
+                error.get_or_insert_with(|| anyhow!("命令 {} 中的占位符 {{{}}} 未定义", cmd, name));
+                caps[0].to_string()
+            })
+        }).to_string();
+        match error {
+            Some(err) => Err(err),
+            None => Ok(result)
+        }
+    }
+
+    pub fn render_cmds(&self, cmds: &[Cmd]) -> Result<Vec<Cmd>> {
+        cmds.iter().map(|x| Ok(Cmd { cmd: self.render(&x.cmd)?, ..x.clone() })).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> Vars {
+        let mut vars = Vars::default();
+        vars.insert("target_name", "app.jar");
+        vars.insert("server.name", "web-1");
+        vars
+    }
+
+    #[test]
+    fn placeholders_skip_shell_braces() {
+        assert_eq!(placeholders("cp {target_name} /opt/{server.name}"), vec!["target_name", "server.name"]);
+        assert!(placeholders("for i in {1..3}; do echo $i; done").is_empty());
+        assert!(placeholders("echo ${HOME} {a,b} { x }").is_empty());
+        assert!(placeholders("awk '{{print}}' f").is_empty());
+        assert_eq!(placeholders("awk '{print}' f"), vec!["print"]);
+    }
+
+    #[test]
+    fn render_replaces_defined_names() {
+        let vars = vars();
+        assert_eq!(vars.render("ls {target_name} ${HOME}").unwrap(), "ls app.jar ${HOME}");
+        assert_eq!(vars.render("for i in {1..3}; do :; done").unwrap(), "for i in {1..3}; do :; done");
+        assert_eq!(vars.render("awk '{{print $1}}' {server.name}").unwrap(), "awk '{print $1}' web-1");
+        assert_eq!(vars.render("awk '{if(x){{print}}}'").unwrap(), "awk '{if(x){print}}'");
+    }
+
+    #[test]
+    fn render_rejects_undefined_names() {
+        let err = vars().render("echo {missing}").unwrap_err();
+        assert!(err.to_string().contains("{missing}"));
+    }
+
+    #[test]
+    fn check_name_rules() {
+        assert!(check_name("app_name-1").is_ok());
+        assert!(check_name("1app").is_err());
+        assert!(check_name("a.b").is_err());
+        assert!(check_name("target_name").is_err());
+    }
+}